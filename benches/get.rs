@@ -4,7 +4,10 @@ use criterion::{black_box, criterion_group, Criterion};
 use crossbeam_utils::sync::WaitGroup;
 use ferris_log::{
     concurrency::{rayon::RayonThreadPool, shared::SharedQueueThreadPool, ThreadPool},
-    kvstore::KvStore,
+    kvstore::{
+        options::{KvStoreOptions, ReadMode},
+        KvStore,
+    },
 };
 use tempfile::TempDir;
 
@@ -65,6 +68,33 @@ pub fn multi_get_benchmark(c: &mut Criterion) {
     });
 }
 
+pub fn multi_get_mmap_benchmark(c: &mut Criterion) {
+    let data = multi_fake_data();
+    c.bench_function("100 Random Get Operation mmap", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let mut store = KvStoreOptions::new()
+                    .read_mode(ReadMode::Mmap)
+                    .open(temp_dir.path())
+                    .unwrap();
+                for item in &data {
+                    store
+                        .set(black_box(item.0.clone()), black_box(item.1.clone()))
+                        .unwrap();
+                }
+                (store, temp_dir)
+            },
+            |(store, _tempdir)| {
+                for item in &data {
+                    let _ = store.get(black_box(item.0.clone()));
+                }
+            },
+            criterion::BatchSize::LargeInput,
+        )
+    });
+}
+
 pub fn get_benchmark_shared_pool_4_threads(c: &mut Criterion) {
     let data = multi_fake_data();
     let pool = SharedQueueThreadPool::new(4).unwrap();
@@ -249,6 +279,7 @@ criterion_group!(
     get_benches,
     single_get_benchmark,
     multi_get_benchmark,
+    multi_get_mmap_benchmark,
     get_benchmark_shared_pool_4_threads,
     get_benchmark_rayon_pool_4_threads,
     get_benchmark_shared_pool_8_threads,
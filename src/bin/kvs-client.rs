@@ -1,9 +1,16 @@
-use bincode::{config, decode_from_slice, encode_to_vec};
 use clap::{Parser, Subcommand};
+use ferris_log::kvstore::error::KvError;
+use ferris_log::kvstore::expiry::Ttl;
+use ferris_log::server::protocol::{
+    cas_value, read_cas, read_incr, read_pairs, read_status, read_ttl, read_value, ttl_value,
+    write_request, CAS, GET, INCR, REMOVE, REMOVE_IF_EQUALS, SCAN, SCAN_PREFIX, SET, SET_IF_ABSENT,
+    SET_TTL, TTL,
+};
 use serde::Serialize;
 use std::{
-    io::{Read, Write},
+    io::{stdout, Write},
     net::TcpStream,
+    time::Duration,
 };
 
 // Cli Parser
@@ -21,7 +28,14 @@ struct Cli {
 enum Commands {
     #[allow(non_camel_case_types)]
     /// Set a key-value pair
-    set { key: String, val: String },
+    set {
+        key: String,
+        val: String,
+
+        /// Remove the key after this many seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
 
     /// Get the value for a key
     #[allow(non_camel_case_types)]
@@ -30,11 +44,63 @@ enum Commands {
     /// Remove a key-value pair
     #[allow(non_camel_case_types)]
     rm { key: String },
+
+    /// Get how many seconds a key has left to live
+    #[allow(non_camel_case_types)]
+    ttl { key: String },
+
+    /// List the key-value pairs from START up to but not including END, in key order
+    #[allow(non_camel_case_types)]
+    scan {
+        start: Option<String>,
+        end: Option<String>,
+    },
+
+    /// List the key-value pairs whose key starts with PREFIX, in key order
+    #[allow(non_camel_case_types)]
+    scan_prefix { prefix: String },
+
+    /// Replace the value of a key only if it still holds the expected one
+    #[allow(non_camel_case_types)]
+    cas {
+        key: String,
+
+        /// The value the key should hold, leave out to expect it not to be there
+        #[arg(long)]
+        expected: Option<String>,
+
+        /// The value to write, leave out to remove the key
+        #[arg(long)]
+        new: Option<String>,
+    },
+
+    /// Set a key-value pair only if the key isn't there
+    #[allow(non_camel_case_types)]
+    set_if_absent { key: String, val: String },
+
+    /// Remove a key only if it holds the expected value
+    #[allow(non_camel_case_types)]
+    remove_if_equals { key: String, expected: String },
+
+    /// Add to the integer a key holds, starting from 0, and print the new value
+    #[allow(non_camel_case_types)]
+    incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+
+    /// Subtract from the integer a key holds, starting from 0, and print the new value
+    #[allow(non_camel_case_types)]
+    decr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
-    let config = config::standard();
 
     // Return the helping description if they didnt specify any arguments
     if cli.command.is_none() {
@@ -52,95 +118,166 @@ fn main() {
 
     // Match the command
     match cli.command.unwrap() {
-        Commands::set { key, val } => {
-            let command = [0_u8];
-
-            let bytekey = encode_to_vec(key, config).unwrap();
-            let byteval = encode_to_vec(val, config).unwrap();
+        Commands::set { key, val, ttl } => {
+            let _ = match ttl {
+                Some(ttl) => {
+                    let val = ttl_value(Duration::from_secs(ttl), val.as_bytes());
+                    write_request(&mut stream, SET_TTL, key.as_bytes(), &val)
+                }
+                None => write_request(&mut stream, SET, key.as_bytes(), val.as_bytes()),
+            };
+            wait_written(&mut stream);
+        }
 
-            /* println!(
-                "Data sent: {:?} {:?} {:?} {:?} {:?}",
-                command.clone(),
-                bytekey.len(),
-                byteval.len(),
-                bytekey.clone(),
-                byteval.clone()
-            ); */
+        Commands::ttl { key } => {
+            let _ = write_request(&mut stream, TTL, key.as_bytes(), &[]);
+            let _ = stream.shutdown(std::net::Shutdown::Write);
 
-            let _ = stream.write(&command);
-            let _ = stream.write(&[bytekey.len() as u8]);
-            let _ = stream.write(&[byteval.len() as u8]);
-            let _ = stream.write(&bytekey[..]);
-            let _ = stream.write(&byteval[..]);
+            match read_ttl(&mut stream) {
+                Ok(Ttl::Expires(left)) => println!("{}", left.as_millis().div_ceil(1000)),
+                Ok(Ttl::Persistent) => println!("No expiry"),
+                Err(_) => println!("Key not found"),
+            }
         }
 
         Commands::get { key } => {
-            let command = [1_u8];
+            let _ = write_request(&mut stream, GET, key.as_bytes(), &[]);
 
-            let bytekey = encode_to_vec(key, config).unwrap();
+            let _ = stream.shutdown(std::net::Shutdown::Write);
 
-            /* println!(
-                "Data sent: {:?} {:?} {:?} {:?}",
-                command.clone(),
-                bytekey.len(),
-                [0_u8],
-                bytekey.clone(),
-            ); */
+            match read_value(&mut stream) {
+                // NOTE: Values that aren't text are written out as they are, so they can be piped
+                Ok(val) => match String::from_utf8(val) {
+                    Ok(val) => println!("{}", val),
+                    Err(e) => {
+                        let _ = stdout().write_all(e.as_bytes());
+                    }
+                },
+                Err(_) => println!("Key not found"),
+            }
+        }
+
+        Commands::rm { key } => {
+            let _ = write_request(&mut stream, REMOVE, key.as_bytes(), &[]);
+            wait_written(&mut stream);
+        }
 
-            let _ = stream.write(&command);
-            let _ = stream.write(&[bytekey.len() as u8]);
-            let _ = stream.write(&[0_u8]);
-            let _ = stream.write(&bytekey[..]);
-            let _ = stream.write(&[]);
+        Commands::scan { start, end } => {
+            let start = start.unwrap_or_default();
+            let end = end.unwrap_or_default();
+            let _ = write_request(&mut stream, SCAN, start.as_bytes(), end.as_bytes());
+            print_pairs(&mut stream);
+        }
 
-            let _ = stream.shutdown(std::net::Shutdown::Write);
+        Commands::scan_prefix { prefix } => {
+            let _ = write_request(&mut stream, SCAN_PREFIX, prefix.as_bytes(), &[]);
+            print_pairs(&mut stream);
+        }
 
-            let mut size: [u8; 1] = [0];
+        Commands::cas { key, expected, new } => {
+            let val = cas_value(
+                expected.as_ref().map(|val| val.as_bytes()),
+                new.as_ref().map(|val| val.as_bytes()),
+            );
+            let _ = write_request(&mut stream, CAS, key.as_bytes(), &val);
+            print_cas(&mut stream);
+        }
 
-            match stream.read_exact(&mut size) {
-                Ok(_) => (),
-                Err(_) => {
-                    println!("Key not found");
-                    return;
-                }
-            }
+        Commands::set_if_absent { key, val } => {
+            let _ = write_request(&mut stream, SET_IF_ABSENT, key.as_bytes(), val.as_bytes());
+            print_cas(&mut stream);
+        }
 
-            let mut buf: Vec<u8> = Vec::new();
-            match stream.read_to_end(&mut buf) {
-                Ok(_) => (),
-                Err(_) => {
-                    println!("Key not found");
-                    return;
-                }
-            }
+        Commands::remove_if_equals { key, expected } => {
+            let _ = write_request(
+                &mut stream,
+                REMOVE_IF_EQUALS,
+                key.as_bytes(),
+                expected.as_bytes(),
+            );
+            print_cas(&mut stream);
+        }
 
-            let byte: Result<(String, usize), bincode::error::DecodeError> =
-                decode_from_slice(&buf[..], config::standard());
+        Commands::incr { key, delta } => incr(&mut stream, &key, Some(delta)),
 
-            match byte {
-                Ok(b) => println!("{}", b.0),
-                Err(_) => println!("Key not found"),
-            }
+        Commands::decr { key, delta } => incr(&mut stream, &key, delta.checked_neg()),
+    }
+}
+
+fn incr(stream: &mut TcpStream, key: &str, delta: Option<i64>) {
+    let res = match delta {
+        Some(delta) => {
+            let _ = write_request(stream, INCR, key.as_bytes(), &delta.to_le_bytes());
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+            read_incr(stream)
         }
+        None => Ok(Err(KvError::Overflow)),
+    };
 
-        Commands::rm { key } => {
-            let command = [2_u8];
+    match res {
+        Ok(Ok(val)) => println!("{}", val),
+        Ok(Err(KvError::Overflow)) => {
+            println!("Value would overflow");
+            std::process::exit(1);
+        }
+        Ok(Err(_)) => {
+            println!("Value is not an integer");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-            let bytekey = encode_to_vec(key, config).unwrap();
+// NOTE: Only returns once the server answered, so the write is done by the time the client exits
+fn wait_written(stream: &mut TcpStream) {
+    let _ = stream.shutdown(std::net::Shutdown::Write);
 
-            /* println!(
-                "Data sent: {:?} {:?} {:?} {:?}",
-                command.clone(),
-                bytekey.len(),
-                [0_u8],
-                bytekey.clone(),
-            ); */
+    match read_status(stream) {
+        Ok(true) => (),
+        Ok(false) => {
+            eprintln!("ERROR: The write failed");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-            let _ = stream.write(&command);
-            let _ = stream.write(&[bytekey.len() as u8]);
-            let _ = stream.write(&[0_u8]);
-            let _ = stream.write(&bytekey[..]);
-            let _ = stream.write(&[]);
+// NOTE: Exits with 1 when the key held something else, so scripts can tell who won
+fn print_cas(stream: &mut TcpStream) {
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+
+    match read_cas(stream) {
+        Ok(Ok(())) => (),
+        Ok(Err(current)) => {
+            match current {
+                Some(val) => println!("Current value: {}", String::from_utf8_lossy(&val)),
+                None => println!("Key not found"),
+            }
+            std::process::exit(1);
+        }
+        Err(e) => eprintln!("ERROR: {}", e),
+    }
+}
+
+fn print_pairs(stream: &mut TcpStream) {
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+
+    match read_pairs(stream) {
+        Ok(pairs) => {
+            for (key, val) in pairs {
+                println!(
+                    "{}\t{}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&val)
+                );
+            }
         }
+        Err(e) => eprintln!("ERROR: {}", e),
     }
 }
@@ -2,11 +2,19 @@ use clap::Parser;
 use ferris_log::concurrency::ThreadPool;
 use ferris_log::server::engine::Engine;
 use ferris_log::server::handler::handle_connection;
-use ferris_log::{concurrency::naive::NaiveThreadPool, kvstore::KvStore};
+use ferris_log::{
+    concurrency::naive::NaiveThreadPool,
+    kvstore::{
+        crypto::EncryptionKey,
+        options::{KvStoreOptions, SyncPolicy},
+        KvStore,
+    },
+};
 use lazy_static::lazy_static;
 use sled::Db;
-use slog::{info, o, Drain, Logger};
+use slog::{info, o, warn, Drain, Logger};
 use slog_term::PlainSyncDecorator;
+use std::panic::AssertUnwindSafe;
 use std::sync::{Arc, Mutex};
 use std::{env::current_dir, io::stdout, net::TcpListener};
 
@@ -18,6 +26,15 @@ struct Args {
 
     #[arg(short,long, default_value_t=String::from("Kvs"))]
     engine: String,
+
+    /// When the kvs engine flushes writes to the disk: none, always, periodic (every second) or
+    /// periodic:<milliseconds>
+    #[arg(long, default_value = "none")]
+    sync: SyncPolicy,
+
+    /// Bytes of values the kvs engine keeps in memory for reads, 0 for none
+    #[arg(long, default_value_t = 0)]
+    cache_size: usize,
 }
 
 fn main() {
@@ -42,20 +59,14 @@ fn main() {
             };
             Arc::new(Mutex::new(db))
         };
-        pub static ref STORE: Arc<Mutex<KvStore>> = {
-            let wrapped_store = KvStore::open(current_dir().unwrap().as_path());
-            let store = match wrapped_store {
-                Ok(store) => store,
-                Err(e) => panic!("The path cannot be accessed, Error: {}", e),
-            };
-            Arc::new(Mutex::new(store))
-        };
     };
 
     info!(LOGGER,
         "Application started";
         "started_at" => format!("{}", args.addr),
-        "Engine" => &args.engine
+        "Engine" => &args.engine,
+        "sync" => format!("{:?}", args.sync),
+        "cache_size" => args.cache_size
     );
 
     let engine: Engine = args.engine.into();
@@ -86,13 +97,15 @@ fn main() {
     // Match which engine is used
     match engine {
         Engine::Kvs => {
+            // NOTE: Every connection gets its own handle, all of them reading through the same
+            // open segment files, and only writes take the store's writer lock. A request that
+            // panics can't leave the store half changed, the index is only swapped once a write
+            // is done
+            let store = open_store(args.sync, args.cache_size, &LOGGER);
             for stream_wrapped in listener.incoming() {
                 let mut stream = stream_wrapped.unwrap();
-                naive_pool.spawn(move || {
-                    let kvstore_thread = Arc::clone(&STORE);
-                    let mut store_guard = kvstore_thread.lock().unwrap();
-                    handle_connection(&mut stream, &LOGGER, &mut *store_guard)
-                })
+                let mut store = AssertUnwindSafe(store.clone());
+                naive_pool.spawn(move || handle_connection(&mut stream, &LOGGER, &mut *store))
             }
         }
         Engine::Sled => {
@@ -107,3 +120,27 @@ fn main() {
         }
     }
 }
+
+fn open_store(sync: SyncPolicy, cache_size: usize, logger: &Logger) -> KvStore {
+    // NOTE: The store is encrypted with the key from FERRISLOG_KEY or FERRISLOG_KEY_FILE
+    let options = KvStoreOptions::new().sync(sync).cache_size(cache_size);
+    let options = match EncryptionKey::from_env() {
+        Ok(Some(key)) => options.encryption(key),
+        Ok(None) => options,
+        Err(e) => panic!("The encryption key cannot be read, Error: {}", e),
+    };
+    let wrapped_store = options.open(current_dir().unwrap());
+    let store = match wrapped_store {
+        Ok(store) => store,
+        Err(e) => panic!("The path cannot be accessed, Error: {}", e),
+    };
+    if let Some(recovered) = store.recovered() {
+        warn!(logger,
+            "Recovered from an unclean shutdown";
+            "path" => format!("{}", recovered.path.display()),
+            "offset" => recovered.offset,
+            "dropped_bytes" => recovered.dropped
+        );
+    }
+    store
+}
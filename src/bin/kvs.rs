@@ -1,19 +1,53 @@
+use chrono::{DateTime, Local};
 use clap::{Parser, Subcommand};
-use ferris_log::kvstore::KvStore;
-use std::{env::current_dir, path::PathBuf, process::exit, str::FromStr};
+use ferris_log::kvstore::{
+    crypto::EncryptionKey,
+    error::{KvError, KvResult},
+    expiry::Ttl,
+    options::{HistoryPolicy, KvStoreOptions},
+    Scan, Version,
+};
+use std::{
+    env::current_dir,
+    io::{stdout, Write},
+    path::PathBuf,
+    process::exit,
+    str::FromStr,
+    time::Duration,
+};
 
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// Keep the last N versions of every key, for the history command
+    #[arg(long = "history", global = true, value_name = "N")]
+    keep_versions: Option<usize>,
+
+    /// Encrypt the store with the key in this file, 32 bytes or 64 hex digits. Falls back to the
+    /// FERRISLOG_KEY and FERRISLOG_KEY_FILE environment variables
+    #[arg(long, global = true, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+
+    /// Open the store only to read it, alongside other read-only opens
+    #[arg(long, global = true)]
+    read_only: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     #[allow(non_camel_case_types)]
     /// Set a key-value pair
-    set { key: String, val: String },
+    set {
+        key: String,
+        val: String,
+
+        /// Remove the key after this many seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
 
     /// Get the value for a key
     #[allow(non_camel_case_types)]
@@ -23,10 +57,45 @@ enum Commands {
     #[allow(non_camel_case_types)]
     rm { key: String },
 
+    /// Get how many seconds a key has left to live
+    #[allow(non_camel_case_types)]
+    ttl { key: String },
+
+    /// List the versions kept of a key, newest first
+    #[allow(non_camel_case_types)]
+    history { key: String },
+
     /// List all keys in the store
     #[allow(non_camel_case_types)]
     list_key,
 
+    /// List the key-value pairs from START up to but not including END, in key order
+    #[allow(non_camel_case_types)]
+    scan {
+        start: Option<String>,
+        end: Option<String>,
+    },
+
+    /// List the key-value pairs whose key starts with PREFIX, in key order
+    #[allow(non_camel_case_types)]
+    scan_prefix { prefix: String },
+
+    /// Add to the integer a key holds, starting from 0, and print the new value
+    #[allow(non_camel_case_types)]
+    incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+
+    /// Subtract from the integer a key holds, starting from 0, and print the new value
+    #[allow(non_camel_case_types)]
+    decr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+
     /// Count the number of keys in the store
     #[allow(non_camel_case_types)]
     count,
@@ -42,19 +111,54 @@ enum Commands {
 
 fn main() {
     let cli = Cli::parse();
-    let mut store = KvStore::open(current_dir().unwrap().as_path()).unwrap();
-
     if cli.command.is_none() {
         Cli::parse_from(["kvs", "--help"]);
         return;
     }
 
+    let mut options = KvStoreOptions::new().read_only(cli.read_only);
+    if let Some(n) = cli.keep_versions {
+        options = options.history(HistoryPolicy::Versions(n));
+    }
+    let key = match &cli.key_file {
+        Some(path) => EncryptionKey::from_file(path).map(Some),
+        None => EncryptionKey::from_env(),
+    };
+    match key {
+        Ok(Some(key)) => options = options.encryption(key),
+        Ok(None) => (),
+        Err(e) => {
+            eprint!("ERROR: {}", e);
+            exit(1);
+        }
+    }
+    let mut store = match options.open(current_dir().unwrap()) {
+        Ok(store) => store,
+        Err(e) => {
+            eprint!("ERROR: {}", e);
+            exit(1);
+        }
+    };
+    if let Some(recovered) = store.recovered() {
+        eprintln!(
+            "Dropped {} bytes of a torn write at offset {} in {}",
+            recovered.dropped,
+            recovered.offset,
+            recovered.path.display()
+        );
+    }
+
     // Your implementation here
     match &cli.command.unwrap() {
         Commands::get { key } => {
-            let val = store.get(key.to_string());
+            let val = store.get_bytes(key.as_bytes());
             match val.unwrap() {
-                Some(d) => println!("{}", d),
+                Some(d) => match String::from_utf8(d) {
+                    Ok(d) => println!("{}", d),
+                    Err(e) => {
+                        let _ = stdout().write_all(e.as_bytes());
+                    }
+                },
                 None => println!("Key not found"),
             }
         }
@@ -71,15 +175,45 @@ fn main() {
             println!("Key removed succesfully");
         }
 
-        Commands::set { key, val } => {
-            let _ = store.set(key.to_string(), val.to_string());
+        Commands::set { key, val, ttl } => {
+            let res = match ttl {
+                Some(ttl) => {
+                    store.set_with_ttl(key.to_string(), val.to_string(), Duration::from_secs(*ttl))
+                }
+                None => store.set(key.to_string(), val.to_string()),
+            };
+            if let Err(e) = res {
+                eprint!("ERROR: {}", e);
+                exit(1);
+            }
             println!("Key set succesfully");
         }
 
+        Commands::ttl { key } => print_ttl(store.ttl(key.to_string()).unwrap()),
+
+        Commands::history { key } => print_history(store.get_history(key.to_string()).unwrap()),
+
         Commands::list_key => {
             store.list_key();
         }
 
+        Commands::scan { start, end } => {
+            let start = start.clone().unwrap_or_default().into_bytes();
+            let pairs = match end {
+                Some(end) => store.scan(start..end.clone().into_bytes()),
+                None => store.scan(start..),
+            };
+            print_pairs(pairs.unwrap());
+        }
+
+        Commands::scan_prefix { prefix } => {
+            print_pairs(store.scan_prefix(prefix.as_bytes()).unwrap());
+        }
+
+        Commands::incr { key, delta } => print_integer(store.incr(key.to_string(), *delta)),
+
+        Commands::decr { key, delta } => print_integer(store.decr(key.to_string(), *delta)),
+
         Commands::count => {
             println!("{}", store.count());
         }
@@ -107,3 +241,59 @@ fn main() {
         }
     }
 }
+
+fn print_pairs(pairs: Scan) {
+    for (key, val) in pairs {
+        println!(
+            "{}\t{}",
+            String::from_utf8_lossy(&key),
+            String::from_utf8_lossy(&val)
+        );
+    }
+}
+
+fn print_integer(val: KvResult<i64>) {
+    match val {
+        Ok(val) => println!("{}", val),
+        Err(KvError::NotAnInteger) => {
+            println!("Value is not an integer");
+            exit(1);
+        }
+        Err(KvError::Overflow) => {
+            println!("Value would overflow");
+            exit(1);
+        }
+        Err(e) => {
+            println!("ERROR: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn print_history(versions: Vec<Version>) {
+    if versions.is_empty() {
+        println!("Key not found");
+    }
+    for version in versions {
+        let written_at = version
+            .written_at
+            .and_then(|at| DateTime::from_timestamp_millis(at as i64))
+            .map_or("unknown".to_owned(), |at| {
+                at.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S%.3f")
+                    .to_string()
+            });
+        match version.value {
+            Some(val) => println!("{}\t{}", written_at, String::from_utf8_lossy(&val)),
+            None => println!("{}\t(removed)", written_at),
+        }
+    }
+}
+
+fn print_ttl(ttl: Option<Ttl>) {
+    match ttl {
+        Some(Ttl::Expires(left)) => println!("{}", left.as_millis().div_ceil(1000)),
+        Some(Ttl::Persistent) => println!("No expiry"),
+        None => println!("Key not found"),
+    }
+}
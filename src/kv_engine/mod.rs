@@ -1,39 +1,450 @@
-use crate::kvstore::{error::KvError, KvStore};
-use std::error::Error;
+use crate::kvstore::{
+    batch::WriteBatch,
+    command::Command,
+    error::{CasResult, CompareAndSwapError, KvError},
+    expiry::{expires_at, now, Ttl},
+    is_backwards, parse_integer,
+    transaction::Transaction,
+    KvStore, Pair,
+};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionError, TransactionalTree,
+        UnabortableTransactionError,
+    },
+    Batch, Transactional,
+};
+use std::{cell::RefCell, error::Error, ops::RangeBounds, time::Duration};
 
-// NOTE: t{command} stands for KvEngine command
+// NOTE: t{command} stands for KvEngine command, keys and values are raw bytes
 pub trait KvEngine: Clone + Send + 'static {
-    fn tget(&self, key: String) -> Result<Option<String>, Box<dyn Error>>;
-    fn tset(&mut self, key: String, val: String) -> Result<(), Box<dyn Error>>;
-    fn tremove(&mut self, key: String) -> Result<(), Box<dyn Error>>;
+    fn tget(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+    fn tset(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Box<dyn Error>>;
+    /// Sets a key that is removed once `ttl` has passed
+    fn tset_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error>>;
+    fn tremove(&mut self, key: Vec<u8>) -> Result<(), Box<dyn Error>>;
+    /// How long a key has left to live, None if it isn't there
+    fn tttl(&self, key: Vec<u8>) -> Result<Option<Ttl>, Box<dyn Error>>;
+    /// Every key in `range` with its value, in key order
+    fn tscan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<Pair>, Box<dyn Error>>;
+    fn tscan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<Pair>, Box<dyn Error>>;
+    /// Applies every write in the batch, or none of them
+    fn tbatch(&mut self, batch: WriteBatch) -> Result<(), Box<dyn Error>>;
+    /// Writes `new` only if the key holds `expected`, None meaning it isn't there on either side.
+    /// Otherwise hands back the value the key holds
+    fn tcompare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasResult, Box<dyn Error>>;
+    fn tset_if_absent(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<CasResult, Box<dyn Error>> {
+        self.tcompare_and_swap(key, None, Some(val))
+    }
+    fn tremove_if_equals(
+        &mut self,
+        key: Vec<u8>,
+        expected: Vec<u8>,
+    ) -> Result<CasResult, Box<dyn Error>> {
+        self.tcompare_and_swap(key, Some(expected), None)
+    }
+    /// Adds `delta` to the integer the key holds, starting from 0, and returns the new value
+    fn tincr(&mut self, key: Vec<u8>, delta: i64) -> Result<i64, Box<dyn Error>>;
+    fn tdecr(&mut self, key: Vec<u8>, delta: i64) -> Result<i64, Box<dyn Error>> {
+        self.tincr(key, delta.checked_neg().ok_or(KvError::Overflow)?)
+    }
+    /// Runs `f` as one transaction, which commits only if none of the keys it read were changed
+    /// by someone else. `f` is run again until that is the case, so it shouldn't have side effects
+    fn ttransaction<T, F>(&mut self, f: F) -> Result<T, Box<dyn Error>>
+    where
+        F: Fn(&mut dyn EngineTransaction) -> Result<T, Box<dyn Error>>;
+}
+
+/// The reads and writes a `KvEngine::ttransaction` can make, the writes are only seen by others
+/// once it commits
+pub trait EngineTransaction {
+    fn tget(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+    fn tset(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Box<dyn Error>>;
+    /// Removing a key that isn't there does nothing
+    fn tremove(&mut self, key: Vec<u8>) -> Result<(), Box<dyn Error>>;
 }
 
 impl KvEngine for KvStore {
-    fn tget(&self, key: String) -> Result<Option<String>, Box<dyn Error>> {
-        Ok(self.get(key)?)
+    fn tget(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.get_bytes(&key)?)
+    }
+    fn tset(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        Ok(self.set_bytes(key, val)?)
+    }
+    fn tset_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(self.set_bytes_with_ttl(key, val, ttl)?)
+    }
+    fn tremove(&mut self, key: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        Ok(self.remove_bytes(&key)?)
+    }
+    fn tttl(&self, key: Vec<u8>) -> Result<Option<Ttl>, Box<dyn Error>> {
+        Ok(self.ttl_bytes(&key)?)
+    }
+    fn tscan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<Pair>, Box<dyn Error>> {
+        Ok(self.scan(range)?.collect())
     }
-    fn tset(&mut self, key: String, val: String) -> Result<(), Box<dyn Error>> {
-        Ok(self.set(key, val)?)
+    fn tscan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<Pair>, Box<dyn Error>> {
+        Ok(self.scan_prefix(&prefix)?.collect())
     }
-    fn tremove(&mut self, key: String) -> Result<(), Box<dyn Error>> {
-        Ok(self.remove(key)?)
+    fn tbatch(&mut self, batch: WriteBatch) -> Result<(), Box<dyn Error>> {
+        Ok(self.write(batch)?)
+    }
+    fn tcompare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasResult, Box<dyn Error>> {
+        Ok(self.compare_and_swap(key, expected, new)?)
+    }
+    fn tincr(&mut self, key: Vec<u8>, delta: i64) -> Result<i64, Box<dyn Error>> {
+        Ok(self.incr_bytes(key, delta)?)
+    }
+    fn ttransaction<T, F>(&mut self, f: F) -> Result<T, Box<dyn Error>>
+    where
+        F: Fn(&mut dyn EngineTransaction) -> Result<T, Box<dyn Error>>,
+    {
+        loop {
+            let mut txn = self.transaction();
+            let res = f(&mut txn)?;
+            match txn.commit() {
+                Ok(()) => return Ok(res),
+                Err(KvError::TransactionConflict) => continue,
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
     }
 }
 
-impl KvEngine for sled::Db {
-    fn tremove(&mut self, key: String) -> Result<(), Box<dyn Error>> {
-        self.remove(key.as_bytes())?;
+impl EngineTransaction for Transaction {
+    fn tget(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(self.get_bytes(&key)?)
+    }
+    fn tset(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.set_bytes(key, val);
+        Ok(())
+    }
+    fn tremove(&mut self, key: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        match self.remove_bytes(&key) {
+            Ok(()) | Err(KvError::RemoveError) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+// NOTE: Sled has no TTLs of its own, so when each key expires is kept in a tree of its own and
+// expired keys are removed the next time they are read
+const SLED_TTL_TREE: &str = "ttl";
+
+fn sled_expires_at(db: &sled::Db, key: &[u8]) -> Result<Option<u64>, Box<dyn Error>> {
+    let at = db.open_tree(SLED_TTL_TREE)?.get(key)?;
+    Ok(at.map(|at| decode_expiry(&at)))
+}
+
+fn decode_expiry(at: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(at);
+    u64::from_le_bytes(bytes)
+}
+
+fn sled_is_expired(db: &sled::Db, key: &[u8], now: u64) -> Result<bool, Box<dyn Error>> {
+    Ok(sled_expires_at(db, key)?.is_some_and(|at| at <= now))
+}
+
+/// Writes `val` under `key`, or removes it if there is none, along with when it expires
+fn sled_write(
+    db: &sled::Db,
+    key: Vec<u8>,
+    val: Option<Vec<u8>>,
+    expires_at: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    // NOTE: A crash between the trees could leave a new value with the TTL of the old one
+    let ttl_tree = db.open_tree(SLED_TTL_TREE)?;
+    let res = (&**db, &ttl_tree).transaction(|(data, ttls)| {
+        match expires_at {
+            Some(at) => ttls.insert(key.as_slice(), &at.to_le_bytes())?,
+            None => ttls.remove(key.as_slice())?,
+        };
+        match &val {
+            Some(val) => data.insert(key.as_slice(), val.as_slice())?,
+            None => data.remove(key.as_slice())?,
+        };
+        Ok(())
+    });
+
+    match res {
+        Ok(()) => Ok(()),
+        Err(TransactionError::Abort(())) => Err(Box::new(KvError::EngineError)),
+        Err(TransactionError::Storage(e)) => Err(Box::new(e)),
+    }
+}
+
+/// A transaction over both the data tree and the TTL tree
+struct SledTransaction<'a> {
+    data: &'a TransactionalTree,
+    ttls: &'a TransactionalTree,
+    now: u64,
+    // NOTE: Sled only runs the closure again on a conflict if it gets its own error back
+    conflict: Option<UnabortableTransactionError>,
+}
+
+impl SledTransaction<'_> {
+    fn check<T>(
+        &mut self,
+        res: Result<T, UnabortableTransactionError>,
+    ) -> Result<T, Box<dyn Error>> {
+        res.map_err(|e| {
+            self.conflict = Some(e);
+            Box::new(KvError::EngineError) as Box<dyn Error>
+        })
+    }
+}
+
+impl EngineTransaction for SledTransaction<'_> {
+    fn tget(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let at = self.ttls.get(&key);
+        if self
+            .check(at)?
+            .is_some_and(|at| decode_expiry(&at) <= self.now)
+        {
+            return Ok(None);
+        }
+
+        let val = self.data.get(key);
+        Ok(self.check(val)?.map(|val| val.to_vec()))
+    }
+    fn tset(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let res = self.ttls.remove(key.clone());
+        self.check(res)?;
+        let res = self.data.insert(key, val);
+        self.check(res)?;
         Ok(())
     }
-    fn tset(&mut self, key: String, val: String) -> Result<(), Box<dyn Error>> {
-        self.insert(key.as_bytes(), val.as_bytes())?;
+    fn tremove(&mut self, key: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let res = self.ttls.remove(key.clone());
+        self.check(res)?;
+        let res = self.data.remove(key);
+        self.check(res)?;
         Ok(())
     }
-    fn tget(&self, key: String) -> Result<Option<String>, Box<dyn Error>> {
-        let val = self.get(key.as_bytes())?;
+}
+
+impl KvEngine for sled::Db {
+    fn tremove(&mut self, key: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        sled_write(self, key, None, None)
+    }
+    fn tset(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        sled_write(self, key, Some(val), None)
+    }
+    fn tset_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        sled_write(self, key, Some(val), Some(expires_at(ttl)))
+    }
+    fn tget(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if sled_is_expired(self, &key, now())? {
+            self.clone().tremove(key)?;
+            return Err(Box::new(KvError::EngineError));
+        }
+
+        let val = self.get(key)?;
         match val {
-            Some(val) => Ok(Some(String::from_utf8_lossy(&val.to_vec()[..]).to_string())),
+            Some(val) => Ok(Some(val.to_vec())),
             None => Err(Box::new(KvError::EngineError)),
         }
     }
+    fn tttl(&self, key: Vec<u8>) -> Result<Option<Ttl>, Box<dyn Error>> {
+        let now = now();
+        if !self.contains_key(&key)? || sled_is_expired(self, &key, now)? {
+            return Ok(None);
+        }
+
+        Ok(Some(match sled_expires_at(self, &key)? {
+            Some(at) => Ttl::Expires(Duration::from_millis(at - now)),
+            None => Ttl::Persistent,
+        }))
+    }
+    fn tscan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Vec<Pair>, Box<dyn Error>> {
+        if is_backwards(&range) {
+            return Ok(Vec::new());
+        }
+        let now = now();
+        let mut pairs = Vec::new();
+        for pair in self.range(range) {
+            let (key, val) = pair?;
+            if !sled_is_expired(self, &key, now)? {
+                pairs.push((key.to_vec(), val.to_vec()));
+            }
+        }
+        Ok(pairs)
+    }
+    fn tscan_prefix(&self, prefix: Vec<u8>) -> Result<Vec<Pair>, Box<dyn Error>> {
+        let now = now();
+        let mut pairs = Vec::new();
+        for pair in self.scan_prefix(prefix) {
+            let (key, val) = pair?;
+            if !sled_is_expired(self, &key, now)? {
+                pairs.push((key.to_vec(), val.to_vec()));
+            }
+        }
+        Ok(pairs)
+    }
+    fn tbatch(&mut self, batch: WriteBatch) -> Result<(), Box<dyn Error>> {
+        let mut data = Batch::default();
+        let mut ttls = Batch::default();
+        for cmd in batch.into_ops() {
+            match cmd {
+                Command::Set {
+                    key,
+                    val,
+                    expires_at,
+                } => {
+                    match expires_at {
+                        Some(at) => ttls.insert(key.clone(), &at.to_le_bytes()),
+                        None => ttls.remove(key.clone()),
+                    }
+                    data.insert(key, val);
+                }
+                Command::Remove { key } => {
+                    ttls.remove(key.clone());
+                    data.remove(key);
+                }
+                _ => (),
+            }
+        }
+
+        // NOTE: Both trees are written in one transaction so a key never loses its TTL halfway
+        let ttl_tree = self.open_tree(SLED_TTL_TREE)?;
+        (&**self, &ttl_tree)
+            .transaction(|(data_tree, ttl_tree)| {
+                data_tree.apply_batch(&data)?;
+                ttl_tree.apply_batch(&ttls)?;
+                Ok(())
+            })
+            .map_err(|_: TransactionError| KvError::EngineError)?;
+        Ok(())
+    }
+    fn tcompare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasResult, Box<dyn Error>> {
+        // NOTE: Both trees are read and written in one transaction, so the key can't expire or
+        // be written between the compare and the swap
+        let ttl_tree = self.open_tree(SLED_TTL_TREE)?;
+        let res = (&**self, &ttl_tree).transaction(|(data, ttls)| {
+            let expired = ttls
+                .get(&key)?
+                .is_some_and(|at| decode_expiry(&at) <= now());
+            if expired {
+                ttls.remove(key.as_slice())?;
+                data.remove(key.as_slice())?;
+            }
+
+            let current = data.get(&key)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(Err(CompareAndSwapError {
+                    current: current.map(|val| val.to_vec()),
+                    proposed: new.clone(),
+                }));
+            }
+
+            // NOTE: What is written, or the removal, never keeps the TTL the old value had
+            ttls.remove(key.as_slice())?;
+            match &new {
+                Some(val) => data.insert(key.as_slice(), val.as_slice())?,
+                None => data.remove(key.as_slice())?,
+            };
+            Ok(Ok(()))
+        });
+
+        match res {
+            Ok(res) => Ok(res),
+            Err(TransactionError::Abort(())) => Err(Box::new(KvError::EngineError)),
+            Err(TransactionError::Storage(e)) => Err(Box::new(e)),
+        }
+    }
+    fn tincr(&mut self, key: Vec<u8>, delta: i64) -> Result<i64, Box<dyn Error>> {
+        let ttl_tree = self.open_tree(SLED_TTL_TREE)?;
+        let res = (&**self, &ttl_tree).transaction(|(data, ttls)| {
+            let expired = ttls
+                .get(&key)?
+                .is_some_and(|at| decode_expiry(&at) <= now());
+            let current = match data.get(&key)? {
+                Some(val) if !expired => {
+                    parse_integer(&val).map_err(ConflictableTransactionError::Abort)?
+                }
+                // NOTE: A fresh key doesn't keep a TTL an expired one left behind
+                _ => {
+                    ttls.remove(key.as_slice())?;
+                    0
+                }
+            };
+            let new = current
+                .checked_add(delta)
+                .ok_or(ConflictableTransactionError::Abort(KvError::Overflow))?;
+
+            data.insert(key.as_slice(), new.to_string().into_bytes())?;
+            Ok(new)
+        });
+
+        match res {
+            Ok(new) => Ok(new),
+            Err(TransactionError::Abort(e)) => Err(Box::new(e)),
+            Err(TransactionError::Storage(e)) => Err(Box::new(e)),
+        }
+    }
+    fn ttransaction<T, F>(&mut self, f: F) -> Result<T, Box<dyn Error>>
+    where
+        F: Fn(&mut dyn EngineTransaction) -> Result<T, Box<dyn Error>>,
+    {
+        // NOTE: Errors from `f` aren't Send, so they are kept here while sled aborts
+        let failed = RefCell::new(None);
+        let ttl_tree = self.open_tree(SLED_TTL_TREE)?;
+        let res = (&**self, &ttl_tree).transaction(|(data, ttls)| {
+            let mut txn = SledTransaction {
+                data,
+                ttls,
+                now: now(),
+                conflict: None,
+            };
+            match f(&mut txn) {
+                Ok(res) => Ok(res),
+                Err(e) => match txn.conflict.take() {
+                    Some(conflict) => Err(conflict.into()),
+                    None => {
+                        failed.replace(Some(e));
+                        Err(ConflictableTransactionError::Abort(()))
+                    }
+                },
+            }
+        });
+
+        match res {
+            Ok(res) => Ok(res),
+            Err(TransactionError::Abort(())) => Err(failed
+                .into_inner()
+                .unwrap_or_else(|| Box::new(KvError::EngineError))),
+            Err(TransactionError::Storage(e)) => Err(Box::new(e)),
+        }
+    }
 }
@@ -0,0 +1,76 @@
+use super::{
+    command::Command,
+    error::{KvError, KvResult},
+    expiry::expires_at,
+};
+use std::{convert::TryFrom, time::Duration};
+
+/// Sets and removes that are written to the log together and applied all or nothing, see
+/// `KvStore::write`
+///
+/// ```no_run
+/// use ferris_log::kvstore::{batch::WriteBatch, KvStore};
+///
+/// let mut store = KvStore::open("data").unwrap();
+/// let mut batch = WriteBatch::new();
+/// batch.set(b"account:1".to_vec(), b"50".to_vec());
+/// batch.set(b"account:2".to_vec(), b"150".to_vec());
+/// batch.remove(b"transfer:7".to_vec());
+/// store.write(batch).unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<Command>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        self.ops.push(Command::set(key, val));
+    }
+
+    /// The TTL starts counting when the set is added to the batch, not when the batch is written
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) {
+        self.ops
+            .push(Command::set_expiring(key, val, expires_at(ttl)));
+    }
+
+    /// Removing a key that isn't there is not an error in a batch, the removal does nothing
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(Command::rm(key));
+    }
+
+    pub fn ops(&self) -> &[Command] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<Command> {
+        self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Only sets and removes can be put in a batch, anything else fails with `InvalidBatch`
+impl TryFrom<Vec<Command>> for WriteBatch {
+    type Error = KvError;
+
+    fn try_from(ops: Vec<Command>) -> KvResult<WriteBatch> {
+        if !ops
+            .iter()
+            .all(|cmd| matches!(cmd, Command::Set { .. } | Command::Remove { .. }))
+        {
+            return Err(KvError::InvalidBatch);
+        }
+        Ok(WriteBatch { ops })
+    }
+}
@@ -0,0 +1,208 @@
+use super::{
+    crypto::{key_id, Keyring},
+    error::{KvError, KvResult},
+    segment::{read_exact_at, sync_dir},
+};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/* NOTE:
+ *   Values of at least `KvStoreOptions::blob_threshold` bytes are written to blob files, as
+ *   `<id>.<name>.blob`, and their record in the log only says where the value is
+ *   | file: u64 | offset: u64 | len: u64 | crc32: u32 |
+ *   all little endian, with the crc covering the value as it is in the file. A blob file is its
+ *   magic followed by the values one after the other. In a file with the sealed magic every value
+ *   is sealed on its own, with its key as the associated data, see `Keyring::seal`
+ *
+ *   Merging the log only copies the references. A blob file is only rewritten once enough of it is
+ *   garbage, by a merge that copies its live values into a new blob file as it goes
+ */
+pub const BLOB_MAGIC: &[u8; 5] = b"FBLB\x01";
+
+pub const SEALED_BLOB_MAGIC: &[u8; 5] = b"FBLE\x01";
+
+pub const BLOB_REF_LEN: usize = 28;
+
+/// A sealed blob file is rewritten by the next merge once more than this much of it is garbage
+pub const BLOB_GC_RATIO: f64 = 0.5;
+
+/// Where a value written to a blob file is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobRef {
+    pub file: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub crc: u32,
+}
+
+impl BlobRef {
+    pub fn encode(&self) -> [u8; BLOB_REF_LEN] {
+        let mut buf = [0; BLOB_REF_LEN];
+        buf[..8].copy_from_slice(&self.file.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.len.to_le_bytes());
+        buf[24..].copy_from_slice(&self.crc.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<BlobRef> {
+        if buf.len() != BLOB_REF_LEN {
+            return None;
+        }
+        let read_u64 = |at: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[at..at + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        Some(BlobRef {
+            file: read_u64(0),
+            offset: read_u64(8),
+            len: read_u64(16),
+            crc: u32::from_le_bytes([buf[24], buf[25], buf[26], buf[27]]),
+        })
+    }
+}
+
+/// How much of a blob file is taken up, how much of that nothing points at anymore and whether
+/// its values are sealed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct BlobUsage {
+    pub size: u64,
+    pub stale: u64,
+    pub sealed: bool,
+}
+
+impl BlobUsage {
+    pub fn is_garbage(&self) -> bool {
+        self.stale as f64 > self.size as f64 * BLOB_GC_RATIO
+    }
+}
+
+/// Appends values to one blob file, which is only created once the first value is written to it
+#[derive(Debug)]
+pub(crate) struct BlobWriter {
+    id: u64,
+    path: PathBuf,
+    file: Option<File>,
+    len: u64,
+}
+
+impl BlobWriter {
+    pub fn new(id: u64, path: PathBuf) -> BlobWriter {
+        BlobWriter {
+            id,
+            path,
+            file: None,
+            len: 0,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes in the file so far, its magic included
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends `val`, sealed for `key` if the store is encrypted, and returns where it went
+    pub fn append(&mut self, keys: Option<&Keyring>, key: &[u8], val: &[u8]) -> KvResult<BlobRef> {
+        let sealed = keys.and_then(|keys| keys.seal(key, val));
+        if self.file.is_none() {
+            let mut f = File::options()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .map_err(|_| KvError::OpenError {
+                    path: self.path.clone(),
+                })?;
+            self.len = f.seek(SeekFrom::End(0)).map_err(|_| KvError::WriteError)?;
+            if self.len == 0 {
+                let magic = match sealed {
+                    Some(_) => SEALED_BLOB_MAGIC,
+                    None => BLOB_MAGIC,
+                };
+                f.write_all(magic).map_err(|_| KvError::WriteError)?;
+                sync_dir(&self.path)?;
+                self.len = magic.len() as u64;
+            }
+            self.file = Some(f);
+        }
+
+        let data = sealed.as_deref().unwrap_or(val);
+        let f = self.file.as_mut().unwrap();
+        if f.write_all(data).is_err() {
+            // NOTE: Don't leave half a value behind for the next one to be appended after
+            let _ = f.set_len(self.len);
+            return Err(KvError::WriteError);
+        }
+
+        let blob = BlobRef {
+            file: self.id,
+            offset: self.len,
+            len: data.len() as u64,
+            crc: crc32fast::hash(data),
+        };
+        self.len += blob.len;
+        Ok(blob)
+    }
+
+    /// Flushes what was written to the disk, if anything was
+    pub fn sync(&self) -> KvResult<()> {
+        match &self.file {
+            Some(f) => f.sync_all().map_err(|_| KvError::WriteError),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Opens a blob file for reading, along with whether its values are sealed
+pub fn open_blob(path: &Path) -> KvResult<(File, bool)> {
+    let mut f = File::open(path).map_err(|_| KvError::OpenError {
+        path: path.to_path_buf(),
+    })?;
+
+    let mut magic = [0; BLOB_MAGIC.len()];
+    f.read_exact(&mut magic).map_err(|_| KvError::ReadError)?;
+    match &magic {
+        BLOB_MAGIC => Ok((f, false)),
+        SEALED_BLOB_MAGIC => Ok((f, true)),
+        _ => Err(KvError::CorruptLog {
+            path: path.to_path_buf(),
+            offset: 0,
+        }),
+    }
+}
+
+/// Reads the value of `key` that `blob` points at, out of a file `open_blob` opened
+pub fn read_blob(
+    f: &File,
+    sealed: bool,
+    keys: Option<&Keyring>,
+    key: &[u8],
+    blob: BlobRef,
+) -> KvResult<Vec<u8>> {
+    let mut buf = vec![0; blob.len as usize];
+    read_exact_at(f, &mut buf, blob.offset).map_err(|_| KvError::ReadError)?;
+    if crc32fast::hash(&buf) != blob.crc {
+        return Err(KvError::ChecksumError);
+    }
+
+    if !sealed {
+        return Ok(buf);
+    }
+    match keys {
+        Some(keys) => keys.open(key, &buf),
+        None => Err(KvError::WrongKey {
+            key_id: key_id(&buf).ok_or(KvError::ParseError)?,
+        }),
+    }
+}
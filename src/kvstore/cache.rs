@@ -0,0 +1,189 @@
+use super::segment::LogPointer;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+// NOTE: Spread over this many shards so readers of different keys rarely meet on a lock
+const SHARDS: usize = 16;
+
+/// What an entry costs on top of its value, so a cache full of tiny values still stays bounded
+pub const ENTRY_OVERHEAD: usize = 64;
+
+/// How the value cache is doing, see `KvStore::cache_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Values pushed out to make room for others
+    pub evictions: u64,
+    pub entries: u64,
+    /// What the cached values take up, counting `ENTRY_OVERHEAD` for each
+    pub bytes: u64,
+}
+
+// NOTE: Where a record is in the log, which never holds anything else
+type Location = (u64, u64);
+
+#[derive(Debug)]
+struct Slot {
+    location: Location,
+    value: Vec<u8>,
+    // NOTE: Set on every hit, the clock hand clears it and evicts the slot on its next pass
+    referenced: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    slots: Vec<Slot>,
+    map: HashMap<Location, usize>,
+    hand: usize,
+    bytes: usize,
+}
+
+impl Shard {
+    fn remove_at(&mut self, i: usize) -> Slot {
+        let slot = self.slots.swap_remove(i);
+        self.map.remove(&slot.location);
+        if let Some(moved) = self.slots.get(i) {
+            self.map.insert(moved.location, i);
+        }
+        self.bytes -= cost(&slot.value);
+        slot
+    }
+}
+
+/* NOTE:
+ *   A CLOCK cache of the values read from the log, bounded in bytes. Values are cached by where
+ *   their record is rather than by key, so a key that was written again is simply looked up
+ *   somewhere else and can never be served an old value. Records that went stale or were merged
+ *   away are still dropped so they don't take up room, and one a reader puts back right after
+ *   is only ever evicted. A hit only takes a shard's read lock
+ */
+#[derive(Debug)]
+pub(crate) struct ValueCache {
+    shards: Vec<RwLock<Shard>>,
+    shard_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            shard_capacity: capacity / SHARDS,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, location: Location) -> &RwLock<Shard> {
+        let hash = (location.0.rotate_left(32) ^ location.1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.shards[(hash >> 60) as usize % SHARDS]
+    }
+
+    pub fn get(&self, pointer: &LogPointer) -> Option<Vec<u8>> {
+        let location = (pointer.segment, pointer.offset);
+        let shard = self.shard(location).read().unwrap();
+        match shard.map.get(&location) {
+            Some(i) => {
+                let slot = &shard.slots[*i];
+                slot.referenced.store(true, Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(slot.value.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, pointer: &LogPointer, value: &[u8]) {
+        let cost = cost(value);
+        if cost > self.shard_capacity {
+            return;
+        }
+
+        let location = (pointer.segment, pointer.offset);
+        let mut shard = self.shard(location).write().unwrap();
+        if shard.map.contains_key(&location) {
+            return;
+        }
+
+        while shard.bytes + cost > self.shard_capacity {
+            let i = shard.hand % shard.slots.len();
+            if shard.slots[i].referenced.swap(false, Ordering::Relaxed) {
+                shard.hand = i + 1;
+                continue;
+            }
+            // NOTE: The last slot takes the evicted one's place, so the hand stays put
+            shard.remove_at(i);
+            shard.hand = i;
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let i = shard.slots.len();
+        shard.slots.push(Slot {
+            location,
+            value: value.to_vec(),
+            referenced: AtomicBool::new(false),
+        });
+        shard.map.insert(location, i);
+        shard.bytes += cost;
+    }
+
+    /// Drops the value of a record that a later write made stale
+    pub fn remove(&self, pointer: &LogPointer) {
+        let location = (pointer.segment, pointer.offset);
+        let mut shard = self.shard(location).write().unwrap();
+        if let Some(i) = shard.map.get(&location).copied() {
+            shard.remove_at(i);
+        }
+    }
+
+    /// Drops every value in segments up to `sealed`, once a merge has moved them
+    pub fn remove_segments(&self, sealed: u64) {
+        for shard in &self.shards {
+            let mut shard = shard.write().unwrap();
+            let mut i = 0;
+            while i < shard.slots.len() {
+                if shard.slots[i].location.0 <= sealed {
+                    shard.remove_at(i);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            *shard.write().unwrap() = Shard::default();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (entries, bytes) = self.shards.iter().fold((0, 0), |(entries, bytes), shard| {
+            let shard = shard.read().unwrap();
+            (entries + shard.slots.len(), bytes + shard.bytes)
+        });
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: entries as u64,
+            bytes: bytes as u64,
+        }
+    }
+}
+
+fn cost(value: &[u8]) -> usize {
+    value.len() + ENTRY_OVERHEAD
+}
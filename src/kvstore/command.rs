@@ -1,14 +1,368 @@
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+use super::{
+    blob::BlobRef,
+    crypto::{key_id, Keyring, SEAL_OVERHEAD},
+    error::{KvError, KvResult},
+    options::Compression,
+};
+
+/* NOTE:
+ *   Every record on disk is laid out as
+ *   | crc32: u32 | kind: u8 | key_len: u32 | val_len: u32 | key | val |
+ *   all integers are little endian, and the crc covers everything after itself. A set with a TTL
+ *   has its own kind and puts the time it expires at, in milliseconds since the unix epoch, first
+ *   | crc32: u32 | kind: u8 | key_len: u32 | val_len: u32 | expires_at: u64 | key | val |
+ *   The records of a write batch sit between a begin marker, whose value is how many records the
+ *   batch holds, and a commit marker. Neither has a key. Records written while history is kept
+ *   have the TIMESTAMPED bit set in their kind and put the time they were written, in the same
+ *   unit, before everything else in the body
+ *   | crc32: u32 | kind: u8 | key_len: u32 | val_len: u32 | written_at: u64 | ... |
+ *   A set whose value was compressed has the COMPRESSED bit set in its kind, then val is the LZ4
+ *   block with the length of the value in front of it as a u32 and val_len is the length of that.
+ *   An encrypted record has the ENCRYPTED bit set and its whole body sealed, see `Keyring::seal`,
+ *   with the header after the crc as the associated data
+ *   | crc32: u32 | kind: u8 | key_len: u32 | val_len: u32 | key_id: u32 | nonce | body | tag |
+ *   A set whose value went to a blob file has the BLOB bit set, then val is where the value is,
+ *   see `blob.rs`
+ */
+pub const HEADER_LEN: usize = 13;
+
+const SET: u8 = 0;
+const REMOVE: u8 = 1;
+const SET_EXPIRING: u8 = 2;
+const BATCH_BEGIN: u8 = 3;
+const BATCH_COMMIT: u8 = 4;
+const TIMESTAMPED: u8 = 0x80;
+const COMPRESSED: u8 = 0x40;
+const ENCRYPTED: u8 = 0x20;
+const BLOB: u8 = 0x10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
-    Set { key: String, val: String },
-    Remove { key: String },
+    Set {
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    /// A set whose value was written to a blob file
+    SetBlob {
+        key: Vec<u8>,
+        blob: BlobRef,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    BatchBegin {
+        count: u32,
+    },
+    BatchCommit,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecordHeader {
+    pub crc: u32,
+    pub kind: u8,
+    pub key_len: u32,
+    pub val_len: u32,
+}
+
+impl RecordHeader {
+    // NOTE: `buf` must hold at least HEADER_LEN bytes
+    pub fn parse(buf: &[u8]) -> RecordHeader {
+        RecordHeader {
+            crc: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            kind: buf[4],
+            key_len: u32::from_le_bytes([buf[5], buf[6], buf[7], buf[8]]),
+            val_len: u32::from_le_bytes([buf[9], buf[10], buf[11], buf[12]]),
+        }
+    }
+
+    /// Length of everything that follows the header
+    pub fn body_len(&self) -> usize {
+        let written_at = if self.is_timestamped() { 8 } else { 0 };
+        let expiry = if self.command_kind() == SET_EXPIRING {
+            8
+        } else {
+            0
+        };
+        let sealed = if self.is_encrypted() {
+            SEAL_OVERHEAD
+        } else {
+            0
+        };
+        sealed + written_at + expiry + self.key_len as usize + self.val_len as usize
+    }
+
+    pub fn is_timestamped(&self) -> bool {
+        self.kind & TIMESTAMPED != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.kind & COMPRESSED != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.kind & ENCRYPTED != 0
+    }
+
+    pub fn is_blob(&self) -> bool {
+        self.kind & BLOB != 0
+    }
+
+    /// The kind without the flag bits
+    fn command_kind(&self) -> u8 {
+        self.kind & !(TIMESTAMPED | COMPRESSED | ENCRYPTED | BLOB)
+    }
 }
 
 impl Command {
-    pub fn set(key: String, val: String) -> Command {
-        Command::Set { key, val }
+    pub fn set(key: Vec<u8>, val: Vec<u8>) -> Command {
+        Command::Set {
+            key,
+            val,
+            expires_at: None,
+        }
+    }
+    pub fn set_expiring(key: Vec<u8>, val: Vec<u8>, expires_at: u64) -> Command {
+        Command::Set {
+            key,
+            val,
+            expires_at: Some(expires_at),
+        }
     }
-    pub fn rm(key: String) -> Command {
+    pub fn rm(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
+
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires_at, .. } | Command::SetBlob { expires_at, .. } => *expires_at,
+            _ => None,
+        }
+    }
+
+    /// Where the value is, for a set whose value went to a blob file
+    pub fn blob(&self) -> Option<BlobRef> {
+        match self {
+            Command::SetBlob { blob, .. } => Some(*blob),
+            _ => None,
+        }
+    }
+
+    /// The same set, with its value written to `blob` instead
+    pub fn with_blob(&self, blob: BlobRef) -> Command {
+        match self {
+            Command::Set {
+                key, expires_at, ..
+            }
+            | Command::SetBlob {
+                key, expires_at, ..
+            } => Command::SetBlob {
+                key: key.clone(),
+                blob,
+                expires_at: *expires_at,
+            },
+            cmd => cmd.clone(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(None, Compression::None)
+    }
+
+    /// Encodes the record stamped with the time it was written, if there is one, and with its
+    /// value compressed if `compression` calls for it and it comes out smaller
+    pub fn encode_with(&self, written_at: Option<u64>, compression: Compression) -> Vec<u8> {
+        let count;
+        let blob;
+        let mut flags = 0;
+        let (kind, key, val) = match self {
+            Command::Set {
+                key,
+                val,
+                expires_at: None,
+            } => (SET, &key[..], &val[..]),
+            Command::Set { key, val, .. } => (SET_EXPIRING, &key[..], &val[..]),
+            Command::SetBlob {
+                key,
+                blob: at,
+                expires_at,
+            } => {
+                blob = at.encode();
+                flags |= BLOB;
+                let kind = if expires_at.is_some() {
+                    SET_EXPIRING
+                } else {
+                    SET
+                };
+                (kind, &key[..], &blob[..])
+            }
+            Command::Remove { key } => (REMOVE, &key[..], &[][..]),
+            Command::BatchBegin { count: n } => {
+                count = n.to_le_bytes();
+                (BATCH_BEGIN, &[][..], &count[..])
+            }
+            Command::BatchCommit => (BATCH_COMMIT, &[][..], &[][..]),
+        };
+
+        if written_at.is_some() {
+            flags |= TIMESTAMPED;
+        }
+        let compressed;
+        let val = match compression {
+            Compression::Lz4 { min_size }
+                if kind <= SET_EXPIRING && flags & BLOB == 0 && val.len() >= min_size =>
+            {
+                compressed = lz4_flex::compress_prepend_size(val);
+                if compressed.len() < val.len() {
+                    flags |= COMPRESSED;
+                    &compressed[..]
+                } else {
+                    val
+                }
+            }
+            _ => val,
+        };
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + 16 + key.len() + val.len());
+        buf.extend_from_slice(&[0; 4]);
+        buf.push(kind | flags);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(val.len() as u32).to_le_bytes());
+        if let Some(written_at) = written_at {
+            buf.extend_from_slice(&written_at.to_le_bytes());
+        }
+        if let Some(expires_at) = self.expires_at() {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(val);
+
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> KvResult<Command> {
+        Ok(Command::decode_at(buf)?.0)
+    }
+
+    /// Decodes the record along with the time it was written, if it was stamped with one
+    pub fn decode_at(buf: &[u8]) -> KvResult<(Command, Option<u64>)> {
+        if buf.len() < HEADER_LEN {
+            return Err(KvError::ParseError);
+        }
+
+        let header = RecordHeader::parse(buf);
+        if buf.len() != HEADER_LEN + header.body_len() {
+            return Err(KvError::ParseError);
+        }
+        if crc32fast::hash(&buf[4..]) != header.crc {
+            return Err(KvError::ChecksumError);
+        }
+
+        let mut body = &buf[HEADER_LEN..];
+        let read_u64 = |body: &mut &[u8]| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&body[..8]);
+            *body = &body[8..];
+            u64::from_le_bytes(bytes)
+        };
+        let written_at = if header.is_timestamped() {
+            Some(read_u64(&mut body))
+        } else {
+            None
+        };
+        let expires_at = if header.command_kind() == SET_EXPIRING {
+            Some(read_u64(&mut body))
+        } else {
+            None
+        };
+        let (key, val) = body.split_at(header.key_len as usize);
+        let key = key.to_vec();
+        let decompressed;
+        let val = if header.is_compressed() {
+            decompressed =
+                lz4_flex::decompress_size_prepended(val).map_err(|_| KvError::ParseError)?;
+            &decompressed[..]
+        } else {
+            val
+        };
+
+        let cmd = match header.command_kind() {
+            SET | SET_EXPIRING if header.is_blob() => Command::SetBlob {
+                key,
+                blob: BlobRef::decode(val).ok_or(KvError::ParseError)?,
+                expires_at,
+            },
+            SET | SET_EXPIRING => Command::Set {
+                key,
+                val: val.to_vec(),
+                expires_at,
+            },
+            REMOVE => Command::Remove { key },
+            BATCH_BEGIN if val.len() == 4 => Command::BatchBegin {
+                count: u32::from_le_bytes([val[0], val[1], val[2], val[3]]),
+            },
+            BATCH_COMMIT => Command::BatchCommit,
+            _ => return Err(KvError::ParseError),
+        };
+        Ok((cmd, written_at))
+    }
+}
+
+/// Seals the body of an encoded record with the current key, if `keys` has one
+pub fn encrypt_record(record: Vec<u8>, keys: Option<&Keyring>) -> Vec<u8> {
+    let keys = match keys {
+        Some(keys) if keys.encrypts() => keys,
+        _ => return record,
+    };
+
+    let mut header = record[4..HEADER_LEN].to_vec();
+    header[0] |= ENCRYPTED;
+    let body = keys.seal(&header, &record[HEADER_LEN..]).unwrap();
+    with_crc(&header, &body)
+}
+
+/// Turns an encrypted record back into the one that was sealed, plain records are returned as
+/// they are. Fails with `WrongKey` if none of `keys` can open it
+pub fn decrypt_record(record: Vec<u8>, keys: Option<&Keyring>) -> KvResult<Vec<u8>> {
+    if record.len() < HEADER_LEN {
+        return Err(KvError::ParseError);
+    }
+    let header = RecordHeader::parse(&record);
+    if !header.is_encrypted() {
+        return Ok(record);
+    }
+    // NOTE: Checked first so a torn record isn't taken for a wrong key
+    if crc32fast::hash(&record[4..]) != header.crc {
+        return Err(KvError::ChecksumError);
+    }
+
+    let sealed = &record[HEADER_LEN..];
+    let body = match keys {
+        Some(keys) => keys.open(&record[4..HEADER_LEN], sealed)?,
+        None => {
+            return Err(KvError::WrongKey {
+                key_id: key_id(sealed).ok_or(KvError::ParseError)?,
+            })
+        }
+    };
+
+    let mut header = record[4..HEADER_LEN].to_vec();
+    header[0] &= !ENCRYPTED;
+    Ok(with_crc(&header, &body))
+}
+
+/// Puts a record back together from the header after its crc and its body
+fn with_crc(header: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(header);
+    buf.extend_from_slice(body);
+
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
 }
@@ -0,0 +1,287 @@
+use super::{
+    blob::{BlobUsage, BlobWriter},
+    command::Command,
+    error::{KvError, KvResult},
+    expiry::now,
+    hint::write_hint,
+    options::Compression,
+    segment::{open_for_append, sync_dir, DataDir, LogPointer, SEGMENT_MAGIC},
+    SharedIndex, Writer,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Runs merges on a background thread, one at a time
+#[derive(Debug, Default)]
+pub struct Compactor {
+    running: Arc<AtomicBool>,
+    handle: Mutex<Option<JoinHandle<KvResult<()>>>>,
+}
+
+impl Compactor {
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Marks a merge as running, returns false if one already is
+    pub fn try_claim(&self) -> bool {
+        self.running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Runs the merge on a new thread, the caller must have claimed it with `try_claim`
+    pub fn start(&self, merge: Merge) {
+        let mut handle = self.handle.lock().unwrap();
+        if let Some(previous) = handle.take() {
+            let _ = previous.join();
+        }
+
+        let running = Arc::clone(&self.running);
+        *handle = Some(thread::spawn(move || {
+            let res = merge.run();
+            running.store(false, Ordering::SeqCst);
+            res
+        }));
+    }
+
+    /// Blocks until the current merge is done and returns how it went
+    pub fn wait(&self) -> KvResult<()> {
+        match self.handle.lock().unwrap().take() {
+            Some(handle) => handle.join().unwrap_or(Err(KvError::WriteError)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // NOTE: Let the merge finish so nobody reopens the directory halfway through it
+        let _ = self.wait();
+    }
+}
+
+// (key, where it was, where it is now or None if it had expired and was dropped)
+type Moved = (Vec<u8>, LogPointer, Option<LogPointer>);
+
+// (key, where a replaced version was, where it is now)
+type Kept = (Vec<u8>, LogPointer, LogPointer);
+
+/// Copies the live records of every sealed segment into one new segment
+pub struct Merge {
+    pub dir: Arc<DataDir>,
+    pub index: Arc<SharedIndex>,
+    pub writer: Arc<Mutex<Writer>>,
+    /// How the records are written to the output, whatever they were written with before
+    pub compression: Compression,
+    /// Every segment up to and including this one is sealed and gets merged
+    pub sealed: u64,
+    /// The id reserved for the output, between the sealed segments and the active one
+    pub output: u64,
+    /// The sealed blob files whose live values are copied into a new one and which are then
+    /// removed, every other value stays where it is
+    pub blobs: Vec<u64>,
+    /// The id reserved for the new blob file, if there are any to rewrite
+    pub blob_output: u64,
+}
+
+impl Merge {
+    pub fn run(self) -> KvResult<()> {
+        /*
+         * The output is written under a temporary name and only renamed into a segment once it is
+         * complete, so a crash halfway leaves the sealed segments untouched
+         */
+        let tmp_path = self.dir.compact(self.output);
+        let mut blobs = BlobWriter::new(self.blob_output, self.dir.compact_blob(self.blob_output));
+        let (moved, kept, output_size) = match self.copy_live(&tmp_path, &mut blobs) {
+            Ok(res) => res,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                let _ = fs::remove_file(blobs.path());
+                return Err(e);
+            }
+        };
+
+        // NOTE: The values go in place before the records that point at them
+        if blobs.len() > 0 {
+            fs::rename(blobs.path(), self.dir.blob(self.blob_output))
+                .map_err(|_| KvError::WriteError)?;
+        }
+        fs::rename(&tmp_path, self.dir.segment(self.output)).map_err(|_| KvError::WriteError)?;
+
+        // NOTE: Without a hint open just replays the output, so a failed one isn't fatal. A hint
+        // only lists the latest write of each key, so there is none when history was kept
+        if kept.is_empty() {
+            let entries: Vec<(Vec<u8>, LogPointer)> = moved
+                .iter()
+                .filter_map(|(key, _, new)| Some((key.clone(), (*new)?)))
+                .collect();
+            let _ = write_hint(&self.dir, self.output, output_size, &entries);
+        }
+        // NOTE: The renames have to be on the disk before the segments they replace are deleted
+        sync_dir(&self.dir.segment(self.output))?;
+
+        let sealed: Vec<u64> = self
+            .dir
+            .segment_ids()?
+            .into_iter()
+            .filter(|id| *id <= self.sealed)
+            .collect();
+        let sealed_size: u64 = sealed.iter().map(|id| self.dir.segment_len(*id)).sum();
+
+        // NOTE: Swap every pointer at once, skipping keys that were written or removed since.
+        // Those copies are garbage in the output already, unless the history policy keeps them
+        {
+            let mut writer = self.writer.lock().unwrap();
+            if blobs.len() > 0 {
+                let usage = BlobUsage {
+                    size: blobs.len(),
+                    stale: 0,
+                    sealed: self.dir.encrypts(),
+                };
+                writer.blobs.insert(self.blob_output, usage);
+            }
+            let mut index = self.index.edit();
+            let retains = index.retains_history();
+            for (key, old, new) in moved {
+                match new {
+                    Some(new) if index.get(&key) == Some(&old) => {
+                        index.relocate(key, new);
+                    }
+                    Some(new) => {
+                        index.relocate_history(&key, old, new);
+                        if !retains {
+                            writer.mark_stale(new);
+                        }
+                    }
+                    None if index.get(&key) == Some(&old) => {
+                        index.forget(&key);
+                    }
+                    None => (),
+                }
+            }
+            for (key, old, new) in kept {
+                index.relocate_history(&key, old, new);
+                if !retains {
+                    writer.mark_stale(new);
+                }
+            }
+
+            self.index.publish(index);
+
+            writer.clear_stale(self.sealed);
+            // NOTE: The output was synced, and the sealed segments and blob files are about to go
+            for id in &sealed {
+                writer.unsynced.remove(&self.dir.segment(*id));
+            }
+            for id in &self.blobs {
+                writer.unsynced.remove(&self.dir.blob(*id));
+                writer.blobs.remove(id);
+            }
+            writer.disk_size = (writer.disk_size + output_size).saturating_sub(sealed_size);
+        }
+
+        for id in sealed {
+            self.dir.remove_segment(id)?;
+        }
+        for id in &self.blobs {
+            self.dir.remove_blob(*id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the live records of the sealed segments to `tmp_path`, along with the replaced
+    /// writes the history policy or open snapshots still need. Returns where each live record and
+    /// each kept write moved, and the size of the output. Records that have expired are left
+    /// behind, unless history is being kept. Values in the blob files being rewritten go to
+    /// `blobs`
+    fn copy_live(
+        &self,
+        tmp_path: &Path,
+        blobs: &mut BlobWriter,
+    ) -> KvResult<(Vec<Moved>, Vec<Kept>, u64)> {
+        // NOTE: (kept writes oldest first, latest write) of every key in the sealed segments
+        let mut keys: BTreeMap<Vec<u8>, (Vec<LogPointer>, Option<LogPointer>)> = BTreeMap::new();
+        let keeps_history = {
+            let _writer = self.writer.lock().unwrap();
+            let mut index = self.index.edit();
+            for (key, pointers) in index.history_in(self.sealed) {
+                keys.entry(key).or_default().0 = pointers;
+            }
+            for (key, pointer) in index.iter() {
+                if pointer.segment <= self.sealed {
+                    keys.entry(key.clone()).or_default().1 = Some(*pointer);
+                }
+            }
+            let keeps_history = index.keeps_history();
+            self.index.publish(index);
+            keeps_history
+        };
+
+        let (mut out, _) = open_for_append(tmp_path)?;
+        let mut offset = SEGMENT_MAGIC.len() as u64;
+        let mut copy = |old: LogPointer| -> KvResult<LogPointer> {
+            let (mut cmd, written_at) = Command::decode_at(&self.dir.read_record(old)?)?;
+            if let Command::SetBlob { key, blob, .. } = &cmd {
+                if self.blobs.contains(&blob.file) {
+                    let val = self.dir.read_blob(key, *blob)?;
+                    cmd = cmd.with_blob(blobs.append(self.dir.keys(), key, &val)?);
+                }
+            }
+            // NOTE: Re-encoding is also what rotates the encryption key
+            let record = self
+                .dir
+                .encrypt(cmd.encode_with(written_at, self.compression));
+
+            out.write_all(&record).map_err(|_| KvError::WriteError)?;
+            // NOTE: Moving a record doesn't change the key, so it keeps its version
+            let new = LogPointer::new(self.output, offset, record.len() as u64)
+                .with_expiry(old.expires_at)
+                .with_version(old.version)
+                .with_written_at(written_at)
+                .with_blob(cmd.blob());
+            offset += new.len;
+            Ok(new)
+        };
+
+        /*
+         * Every key's writes are copied in the order they were made, so replaying the output
+         * without a hint ends on the latest one and rebuilds the history before it
+         */
+        let now = now();
+        let mut moved = Vec::with_capacity(keys.len());
+        let mut kept = Vec::new();
+        for (key, (history, live)) in keys {
+            for old in history {
+                let new = copy(old)?;
+                kept.push((key.clone(), old, new));
+            }
+
+            match live {
+                Some(old) if old.is_expired(now) && !keeps_history => {
+                    moved.push((key, old, None));
+                }
+                Some(old) => {
+                    let new = copy(old)?;
+                    moved.push((key, old, Some(new)));
+                }
+                None => (),
+            }
+        }
+
+        out.sync_all().map_err(|_| KvError::WriteError)?;
+        blobs.sync()?;
+
+        Ok((moved, kept, offset))
+    }
+}
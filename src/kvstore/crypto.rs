@@ -0,0 +1,161 @@
+use super::error::{KvError, KvResult};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use std::{convert::TryFrom, fmt, fs, path::Path};
+
+/// Where the CLIs look for the key, as 64 hex digits
+pub const KEY_ENV: &str = "FERRISLOG_KEY";
+
+/// Where the CLIs look for a key file when `KEY_ENV` isn't set
+pub const KEY_FILE_ENV: &str = "FERRISLOG_KEY_FILE";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// How much longer sealing makes the data, see `Keyring::seal`
+pub const SEAL_OVERHEAD: usize = 4 + NONCE_LEN + TAG_LEN;
+
+/// A 256 bit key for XChaCha20-Poly1305, named by an id that is written next to everything it
+/// encrypts so the right key can be picked after a rotation
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: u32,
+    cipher: XChaCha20Poly1305,
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; KEY_LEN]) -> EncryptionKey {
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        // NOTE: The id is the start of a tag made with the key, so it names the key without
+        // giving any of it away
+        let tag = cipher
+            .encrypt(&XNonce::default(), &[][..])
+            .expect("encrypting nothing can't fail");
+        EncryptionKey {
+            id: u32::from_le_bytes([tag[0], tag[1], tag[2], tag[3]]),
+            cipher,
+        }
+    }
+
+    /// Reads a key written as 64 hex digits
+    pub fn from_hex(hex: &str) -> KvResult<EncryptionKey> {
+        let hex = hex.trim().as_bytes();
+        if hex.len() != KEY_LEN * 2 {
+            return Err(KvError::InvalidKey);
+        }
+
+        let mut key = [0; KEY_LEN];
+        for (byte, digits) in key.iter_mut().zip(hex.chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| KvError::InvalidKey)?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| KvError::InvalidKey)?;
+        }
+
+        Ok(EncryptionKey::new(key))
+    }
+
+    /// Reads a key file, holding either the 32 bytes of the key or 64 hex digits
+    pub fn from_file(path: &Path) -> KvResult<EncryptionKey> {
+        let bytes = fs::read(path).map_err(|_| KvError::OpenError {
+            path: path.to_path_buf(),
+        })?;
+
+        match <[u8; KEY_LEN]>::try_from(&bytes[..]) {
+            Ok(key) => Ok(EncryptionKey::new(key)),
+            Err(_) => EncryptionKey::from_hex(
+                std::str::from_utf8(&bytes).map_err(|_| KvError::InvalidKey)?,
+            ),
+        }
+    }
+
+    /// The key in `KEY_ENV`, or else the one in the file `KEY_FILE_ENV` names, None if neither is
+    /// set
+    pub fn from_env() -> KvResult<Option<EncryptionKey>> {
+        if let Ok(hex) = std::env::var(KEY_ENV) {
+            return EncryptionKey::from_hex(&hex).map(Some);
+        }
+        match std::env::var_os(KEY_FILE_ENV) {
+            Some(path) => EncryptionKey::from_file(Path::new(&path)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({:08x})", self.id)
+    }
+}
+
+impl PartialEq for EncryptionKey {
+    fn eq(&self, other: &EncryptionKey) -> bool {
+        self.id == other.id
+    }
+}
+
+/// The key new data is encrypted with, if any, and every key that can decrypt what is already
+/// on disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyring {
+    current: Option<EncryptionKey>,
+    keys: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    pub fn new(current: Option<EncryptionKey>, old: Vec<EncryptionKey>) -> Keyring {
+        let keys = current.iter().cloned().chain(old).collect();
+        Keyring { current, keys }
+    }
+
+    pub fn encrypts(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Encrypts `data` with the current key, bound to `aad` which has to be given again to
+    /// decrypt it. Lays it out as
+    /// | key_id: u32 | nonce: [u8; 24] | ciphertext | tag: [u8; 16] |
+    pub fn seal(&self, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+        let key = self.current.as_ref()?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = key
+            .cipher
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .expect("encrypting in memory can't fail");
+
+        let mut buf = Vec::with_capacity(SEAL_OVERHEAD + data.len());
+        buf.extend_from_slice(&key.id.to_le_bytes());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&sealed);
+        Some(buf)
+    }
+
+    /// Decrypts what `seal` made, failing with `WrongKey` if none of the keys can
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> KvResult<Vec<u8>> {
+        let id = key_id(sealed).ok_or(KvError::ParseError)?;
+        let key = self.keys.iter().find(|key| key.id == id);
+        let (nonce, msg) = sealed[4..].split_at(NONCE_LEN);
+
+        key.and_then(|key| {
+            key.cipher
+                .decrypt(XNonce::from_slice(nonce), Payload { msg, aad })
+                .ok()
+        })
+        .ok_or(KvError::WrongKey { key_id: id })
+    }
+}
+
+/// The id of the key that sealed `sealed`
+pub fn key_id(sealed: &[u8]) -> Option<u32> {
+    if sealed.len() < SEAL_OVERHEAD {
+        return None;
+    }
+    Some(u32::from_le_bytes([
+        sealed[0], sealed[1], sealed[2], sealed[3],
+    ]))
+}
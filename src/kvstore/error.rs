@@ -11,6 +11,17 @@ pub enum KvError {
     ParseError,
     RemoveError,
     EngineError,
+    ChecksumError,
+    CorruptLog { path: PathBuf, offset: u64 },
+    TransactionConflict,
+    NotAnInteger,
+    Overflow,
+    WrongKey { key_id: u32 },
+    InvalidKey,
+    Locked { path: PathBuf },
+    ReadOnly,
+    InvalidFileName { name: String },
+    InvalidBatch,
 }
 
 impl fmt::Display for KvError {
@@ -22,6 +33,37 @@ impl fmt::Display for KvError {
             KvError::ParseError => writeln!(f, "Parsing has failed!"),
             KvError::RemoveError => writeln!(f, "Unable to remove!"),
             KvError::EngineError => writeln!(f, "None Value is Found, Command has failed!!!"),
+            KvError::ChecksumError => writeln!(f, "Record checksum does not match!"),
+            KvError::CorruptLog { path, offset } => writeln!(
+                f,
+                "Log is corrupt in {} at offset {}!",
+                path.display(),
+                offset
+            ),
+            KvError::TransactionConflict => {
+                writeln!(
+                    f,
+                    "A key the transaction read was changed before it committed!"
+                )
+            }
+            KvError::NotAnInteger => writeln!(f, "Value is not a 64-bit integer!"),
+            KvError::Overflow => writeln!(f, "Value would overflow a 64-bit integer!"),
+            KvError::WrongKey { key_id } => writeln!(
+                f,
+                "Log is encrypted with key {:08x}, which is not one of the keys given!",
+                key_id
+            ),
+            KvError::InvalidKey => writeln!(f, "Encryption key must be 32 bytes or 64 hex digits!"),
+            KvError::Locked { path } => writeln!(
+                f,
+                "Store in {} is already open in another process!",
+                path.display()
+            ),
+            KvError::ReadOnly => writeln!(f, "Store was opened read-only!"),
+            KvError::InvalidFileName { name } => {
+                writeln!(f, "{:?} can't be used as the data file name!", name)
+            }
+            KvError::InvalidBatch => writeln!(f, "A batch can only hold sets and removes!"),
         }
     }
 }
@@ -29,3 +71,21 @@ impl fmt::Display for KvError {
 impl Error for KvError {}
 
 pub type KvResult<T> = Result<T, KvError>;
+
+/// What a compare and swap found instead of the value it expected, None meaning the key wasn't
+/// there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompareAndSwapError {
+    pub current: Option<Vec<u8>>,
+    pub proposed: Option<Vec<u8>>,
+}
+
+impl fmt::Display for CompareAndSwapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Compare and swap found a different value!")
+    }
+}
+
+impl Error for CompareAndSwapError {}
+
+pub type CasResult = Result<(), CompareAndSwapError>;
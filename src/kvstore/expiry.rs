@@ -0,0 +1,130 @@
+use super::{command::Command, error::KvResult, segment::LogPointer, SharedIndex, Writer};
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long a key has left to live, see `KvStore::ttl`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttl {
+    /// The key was set without a TTL
+    Persistent,
+    /// The key expires after this long
+    Expires(Duration),
+}
+
+impl Ttl {
+    pub(crate) fn of(pointer: &LogPointer, now: u64) -> Ttl {
+        match pointer.expires_at {
+            Some(at) => Ttl::Expires(Duration::from_millis(at.saturating_sub(now))),
+            None => Ttl::Persistent,
+        }
+    }
+}
+
+/// Milliseconds since the unix epoch, which is what expiry times are stored as
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// When a key set now with `ttl` expires
+pub fn expires_at(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Writes a removal for every candidate whose key still points at the same expired record,
+/// returning how many there were
+pub(crate) fn expire(
+    writer: &Mutex<Writer>,
+    shared: &SharedIndex,
+    candidates: Vec<(Vec<u8>, LogPointer)>,
+) -> KvResult<usize> {
+    let mut writer = writer.lock().unwrap();
+    // NOTE: Expired keys are already hidden from reads, a read-only store just leaves them
+    if writer.options.read_only {
+        return Ok(0);
+    }
+    let mut index = shared.edit();
+
+    let mut expired = 0;
+    for (key, pointer) in candidates {
+        // NOTE: The key may have been written again since it was picked
+        if index.get(&key) != Some(&pointer) {
+            continue;
+        }
+
+        let tombstone = match writer.append(&Command::rm(key.clone())) {
+            Ok(tombstone) => tombstone,
+            Err(e) => {
+                // NOTE: The removals already written still count
+                shared.publish(index);
+                return Err(e);
+            }
+        };
+        index.remove(&key, tombstone);
+        writer.mark_stale(pointer);
+        writer.mark_stale(tombstone);
+        expired += 1;
+    }
+    shared.publish(index);
+
+    Ok(expired)
+}
+
+/// Removes expired keys on a background thread every `interval`, until it is dropped
+#[derive(Debug)]
+pub(crate) struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn start(
+        writer: Arc<Mutex<Writer>>,
+        index: Arc<SharedIndex>,
+        interval: Duration,
+    ) -> Sweeper {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                /*
+                 * The expired keys are found without a lock, the writer is locked once there is
+                 * something to remove
+                 */
+                let now = now();
+                let candidates: Vec<(Vec<u8>, LogPointer)> = index
+                    .load()
+                    .iter()
+                    .filter(|(_, pointer)| pointer.is_expired(now))
+                    .map(|(key, pointer)| (key.clone(), *pointer))
+                    .collect();
+
+                if !candidates.is_empty() {
+                    let _ = expire(&writer, &index, candidates);
+                }
+            }
+        });
+
+        Sweeper {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        // NOTE: Hanging up wakes the thread up right away instead of at the next sweep
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use super::{
+    error::{KvError, KvResult},
+    Writer,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Flushes the writes made since the last flush to the disk every `interval`, for
+/// `SyncPolicy::Periodic`, and one last time when it is dropped
+#[derive(Debug)]
+pub(crate) struct Flusher {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    pub fn start(writer: Arc<Mutex<Writer>>, interval: Duration) -> Flusher {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let handle = thread::spawn(move || loop {
+            let stopping = !matches!(
+                stopped.recv_timeout(interval),
+                Err(RecvTimeoutError::Timeout)
+            );
+            // NOTE: A failed flush is tried again on the next tick, the writes are still unsynced
+            let _ = writer.lock().unwrap().flush();
+            if stopping {
+                break;
+            }
+        });
+
+        Flusher {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+/* NOTE:
+ *   Group commit for `SyncPolicy::Always`. Writers append under the writer lock without flushing,
+ *   then wait here for their version to be on the disk. The first one to wait leads: it takes every
+ *   segment written to so far and flushes them outside the writer lock, while the writers behind
+ *   it keep appending and queue up. When it is done, every writer its flush covered returns and
+ *   one of the rest leads the next flush, so many writers share each fsync
+ */
+#[derive(Debug, Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<Commits>,
+    flushed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Commits {
+    // NOTE: Every write up to this version is on the disk
+    synced: u64,
+    leading: bool,
+}
+
+impl GroupCommit {
+    /// Blocks until the write with `version`, and every one before it, is on the disk
+    pub fn wait(&self, writer: &Mutex<Writer>, version: u64) -> KvResult<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= version {
+                return Ok(());
+            }
+            if !state.leading {
+                break;
+            }
+            state = self.flushed.wait(state).unwrap();
+        }
+        state.leading = true;
+        drop(state);
+
+        let (through, unsynced) = writer.lock().unwrap().take_unsynced();
+        let flushed = sync_files(&unsynced);
+        if flushed.is_err() {
+            writer.lock().unwrap().restore_unsynced(unsynced);
+        }
+
+        // NOTE: On a failure the waiters wake up still behind, and the next one tries again
+        let mut state = self.state.lock().unwrap();
+        state.leading = false;
+        if flushed.is_ok() {
+            state.synced = state.synced.max(through);
+        }
+        self.flushed.notify_all();
+
+        flushed
+    }
+}
+
+/// Flushes each of the segments and blob files to the disk, skipping the ones merged away since
+pub(crate) fn sync_files(files: &HashMap<PathBuf, u64>) -> KvResult<()> {
+    let mut paths: Vec<&PathBuf> = files.keys().collect();
+    paths.sort_unstable();
+    for path in paths {
+        match File::open(path) {
+            Ok(f) => f.sync_data().map_err(|_| KvError::WriteError)?,
+            // NOTE: What was live in it is in the merge output, which was synced
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(_) => return Err(KvError::WriteError),
+        }
+    }
+
+    Ok(())
+}
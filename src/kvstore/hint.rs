@@ -0,0 +1,130 @@
+use super::{
+    blob::{BlobRef, BLOB_REF_LEN},
+    error::{KvError, KvResult},
+    segment::{DataDir, LogPointer},
+};
+use std::fs::{self, File};
+use std::io::Write;
+
+/* NOTE:
+ *   A hint file sits next to every segment a merge wrote, as `<id>.<name>.hint`, and lists where
+ *   each key in that segment lives so open doesn't have to read the segment itself
+ *   | magic | segment_len: u64 | entries | crc32: u32 |
+ *   where every entry is
+ *   | key_len: u32 | offset: u64 | len: u64 | expires_at: u64 | written_at: u64 | blob | key |
+ *   with an expires_at of 0 for keys that don't expire, a written_at of 0 for records that
+ *   weren't stamped and a blob of zeroes for values kept in the log, see `BlobRef`. All integers
+ *   are little endian, and the crc covers everything before itself. The hint of an encrypted
+ *   store is sealed as a whole, see `Keyring::seal`, behind its own magic
+ *   | sealed magic | sealed hint |
+ */
+pub const HINT_MAGIC: &[u8; 5] = b"FHNT\x04";
+
+pub const SEALED_HINT_MAGIC: &[u8; 5] = b"FHNE\x01";
+
+const ENTRY_LEN: usize = 36 + BLOB_REF_LEN;
+
+/// Writes the hint for segment `id`, which must already be complete and `segment_len` long
+pub fn write_hint(
+    dir: &DataDir,
+    id: u64,
+    segment_len: u64,
+    entries: &[(Vec<u8>, LogPointer)],
+) -> KvResult<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(HINT_MAGIC);
+    buf.extend_from_slice(&segment_len.to_le_bytes());
+    for (key, pointer) in entries {
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&pointer.offset.to_le_bytes());
+        buf.extend_from_slice(&pointer.len.to_le_bytes());
+        buf.extend_from_slice(&pointer.expires_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&pointer.written_at.unwrap_or(0).to_le_bytes());
+        match pointer.blob {
+            Some(blob) => buf.extend_from_slice(&blob.encode()),
+            None => buf.extend_from_slice(&[0; BLOB_REF_LEN]),
+        }
+        buf.extend_from_slice(key);
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    if let Some(sealed) = dir
+        .keys()
+        .and_then(|keys| keys.seal(SEALED_HINT_MAGIC, &buf))
+    {
+        buf = SEALED_HINT_MAGIC.to_vec();
+        buf.extend_from_slice(&sealed);
+    }
+
+    // NOTE: Written under a temporary name first, a torn hint must never be mistaken for a whole
+    // one
+    let path = dir.hint(id);
+    let tmp_path = path.with_extension("hint.compact");
+    let mut f = File::create(&tmp_path).map_err(|_| KvError::OpenError {
+        path: tmp_path.clone(),
+    })?;
+    f.write_all(&buf).map_err(|_| KvError::WriteError)?;
+    f.sync_all().map_err(|_| KvError::WriteError)?;
+
+    fs::rename(&tmp_path, path).map_err(|_| KvError::WriteError)
+}
+
+/// Reads the hint for segment `id`. Returns None when there is no usable hint, then the segment
+/// has to be replayed instead
+pub fn read_hint(dir: &DataDir, id: u64) -> Option<Vec<(Vec<u8>, LogPointer)>> {
+    let mut buf = fs::read(dir.hint(id)).ok()?;
+    if let Some(sealed) = buf.strip_prefix(SEALED_HINT_MAGIC) {
+        // NOTE: Without the right key the segment is replayed, which fails with `WrongKey`
+        buf = dir.keys()?.open(SEALED_HINT_MAGIC, sealed).ok()?;
+    }
+
+    let body_len = buf.len().checked_sub(4)?;
+    if body_len < HINT_MAGIC.len() + 8 || &buf[..HINT_MAGIC.len()] != HINT_MAGIC {
+        return None;
+    }
+    let (body, crc) = buf.split_at(body_len);
+    if crc32fast::hash(body) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return None;
+    }
+
+    let mut pos = HINT_MAGIC.len();
+    let segment_len = read_u64(body, pos)?;
+    // NOTE: Anything appended to the segment after the hint was written isn't in it
+    if segment_len != dir.segment_len(id) {
+        return None;
+    }
+    pos += 8;
+
+    let mut entries = Vec::new();
+    while pos < body.len() {
+        let entry = body.get(pos..pos + ENTRY_LEN)?;
+        let key_len = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+        let offset = read_u64(entry, 4)?;
+        let len = read_u64(entry, 12)?;
+        let expires_at = Some(read_u64(entry, 20)?).filter(|at| *at != 0);
+        let written_at = Some(read_u64(entry, 28)?).filter(|at| *at != 0);
+        // NOTE: Blob files are numbered from 1
+        let blob = BlobRef::decode(&entry[36..]).filter(|blob| blob.file != 0);
+        pos += ENTRY_LEN;
+
+        let key = body.get(pos..pos + key_len)?.to_vec();
+        pos += key_len;
+
+        entries.push((
+            key,
+            LogPointer::new(id, offset, len)
+                .with_expiry(expires_at)
+                .with_written_at(written_at)
+                .with_blob(blob),
+        ));
+    }
+
+    Some(entries)
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> Option<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(buf.get(pos..pos + 8)?);
+    Some(u64::from_le_bytes(bytes))
+}
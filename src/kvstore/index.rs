@@ -0,0 +1,322 @@
+use super::{blob::BlobRef, expiry::now, options::HistoryPolicy, segment::LogPointer};
+use arc_swap::{ArcSwap, Guard};
+use im::{ordmap, OrdMap};
+use std::{
+    collections::{btree_map, BTreeMap, BTreeSet},
+    ops::RangeBounds,
+    sync::Arc,
+};
+
+/// A write of a key that a later one replaced, kept for the history policy or for the snapshots
+/// that can still see it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Write {
+    pub pointer: LogPointer,
+    /// Whether it was a removal, then `pointer` is where its tombstone is
+    pub removed: bool,
+}
+
+impl Write {
+    fn set(pointer: LogPointer) -> Write {
+        Write {
+            pointer,
+            removed: false,
+        }
+    }
+}
+
+/* NOTE:
+ *   Maps every key to its latest record, kept in key order so ranges and prefixes can be scanned.
+ *   The writes that came before it are kept too, oldest first, for as long as the history policy
+ *   or an open snapshot still needs them. A snapshot finds what a key was when it was taken by
+ *   looking for the last write made before it. The maps share their nodes between copies, so
+ *   copying the index to change it, see `SharedIndex`, is cheap
+ */
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Index {
+    keys: OrdMap<Vec<u8>, LogPointer>,
+    history: OrdMap<Vec<u8>, Vec<Write>>,
+    policy: HistoryPolicy,
+    // NOTE: The sequence number of every open snapshot, with how many are open at it
+    snapshots: BTreeMap<u64, usize>,
+    // NOTE: The version of the last write applied, which a new snapshot is pinned to
+    seq: u64,
+}
+
+impl Index {
+    pub fn with_history(policy: HistoryPolicy) -> Index {
+        Index {
+            policy,
+            ..Index::default()
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&LogPointer> {
+        self.keys.get(key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, pointer: LogPointer) -> Option<LogPointer> {
+        self.seq = self.seq.max(pointer.version);
+        let old = self.keys.insert(key.clone(), pointer);
+        let replaced: Vec<Write> = old.into_iter().map(Write::set).collect();
+        self.record(&key, &replaced);
+        old
+    }
+
+    /// Removes `key` for the removal whose tombstone is at `tombstone`
+    pub fn remove(&mut self, key: &[u8], tombstone: LogPointer) -> Option<LogPointer> {
+        self.seq = self.seq.max(tombstone.version);
+        let old = self.keys.remove(key);
+        if let Some(old) = old {
+            let removal = Write {
+                pointer: tombstone,
+                removed: true,
+            };
+            self.record(key, &[Write::set(old), removal]);
+        }
+        old
+    }
+
+    /// Points `key` at where its record was moved to, which doesn't make it a new version
+    pub fn relocate(&mut self, key: Vec<u8>, pointer: LogPointer) {
+        self.keys.insert(key, pointer);
+    }
+
+    /// Drops an expired key that no snapshot can see anymore
+    pub fn forget(&mut self, key: &[u8]) {
+        self.keys.remove(key);
+    }
+
+    fn record(&mut self, key: &[u8], writes: &[Write]) {
+        if self.keeps_history() && !writes.is_empty() {
+            self.history
+                .entry(key.to_vec())
+                .or_default()
+                .extend_from_slice(writes);
+        }
+        if self.history.contains_key(key) {
+            self.prune(key, now());
+        }
+    }
+
+    /// Every write of `key` that is kept, oldest first and ending with the current one
+    fn writes(&self, key: &[u8]) -> Vec<Write> {
+        let history = self.history.get(key).into_iter().flatten().copied();
+        history
+            .chain(self.keys.get(key).copied().map(Write::set))
+            .collect()
+    }
+
+    /// Whether the history policy keeps the `i`th of `writes`, the current write always is
+    fn policy_keeps(&self, writes: &[Write], i: usize, now: u64) -> bool {
+        let next = match writes.get(i + 1) {
+            Some(next) => next,
+            None => return true,
+        };
+        match self.policy {
+            HistoryPolicy::Disabled => false,
+            HistoryPolicy::Versions(n) => writes.len() - i <= n,
+            // NOTE: A write stopped being the latest one when the next one was made
+            HistoryPolicy::Window(window) => {
+                next.pointer.written_at.unwrap_or(0) + window.as_millis() as u64 > now
+            }
+        }
+    }
+
+    /// Drops the oldest writes of `key` that neither the policy nor an open snapshot needs
+    fn prune(&mut self, key: &[u8], now: u64) {
+        let writes = self.writes(key);
+        let oldest = self.snapshots.keys().next().copied();
+        let needed = |i: usize| {
+            // NOTE: A snapshot needs every write that was still the latest when it was taken
+            let seen = oldest.is_some_and(|seq| {
+                writes
+                    .get(i + 1)
+                    .is_none_or(|next| next.pointer.version > seq)
+            });
+            seen || self.policy_keeps(&writes, i, now)
+        };
+        let dropped = (0..writes.len()).take_while(|i| !needed(*i)).count();
+
+        let live = self.keys.contains_key(key);
+        if let ordmap::Entry::Occupied(mut history) = self.history.entry(key.to_vec()) {
+            let kept = history.get_mut();
+            kept.drain(..dropped.min(kept.len()));
+            // NOTE: A removal with nothing before it is the same as the key never being there
+            let only_removal = !live && kept.len() == 1 && kept[0].removed;
+            if kept.is_empty() || only_removal {
+                history.remove();
+            }
+        }
+    }
+
+    fn prune_all(&mut self) {
+        if !self.keeps_history() {
+            self.history.clear();
+            return;
+        }
+
+        let now = now();
+        let keys: Vec<Vec<u8>> = self.history.keys().cloned().collect();
+        for key in keys {
+            self.prune(&key, now);
+        }
+    }
+
+    pub fn range<K, R>(&self, range: R) -> ordmap::Iter<'_, Vec<u8>, LogPointer>
+    where
+        K: Ord + ?Sized,
+        Vec<u8>: std::borrow::Borrow<K>,
+        R: RangeBounds<K>,
+    {
+        self.keys.range(range)
+    }
+
+    pub fn iter(&self) -> ordmap::Iter<'_, Vec<u8>, LogPointer> {
+        self.keys.iter()
+    }
+
+    pub fn values(&self) -> ordmap::Values<'_, Vec<u8>, LogPointer> {
+        self.keys.values()
+    }
+
+    /// Where the value of every write the index still points at is, for the ones in blob files
+    pub fn blobs(&self) -> impl Iterator<Item = BlobRef> + '_ {
+        let history = self.history.values().flatten().map(|write| &write.pointer);
+        self.keys
+            .values()
+            .chain(history)
+            .filter_map(|pointer| pointer.blob)
+    }
+
+    /// Forgets every key along with its history
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.history.clear();
+    }
+
+    /// Pins a snapshot to the last write applied, returning its sequence number
+    pub fn open_snapshot(&mut self) -> u64 {
+        *self.snapshots.entry(self.seq).or_default() += 1;
+        self.seq
+    }
+
+    /// Lets go of the versions only the closed snapshot still needed
+    pub fn close_snapshot(&mut self, seq: u64) {
+        if let btree_map::Entry::Occupied(mut open) = self.snapshots.entry(seq) {
+            *open.get_mut() -= 1;
+            if *open.get() == 0 {
+                open.remove();
+            }
+        }
+        self.prune_all();
+    }
+
+    /// Whether replaced writes are being kept, for the history policy or for a snapshot
+    pub fn keeps_history(&self) -> bool {
+        self.policy.is_enabled() || !self.snapshots.is_empty()
+    }
+
+    /// Whether the history policy keeps replaced writes even with no snapshot open
+    pub fn retains_history(&self) -> bool {
+        self.policy.is_enabled()
+    }
+
+    /// Where `key` was as of the write with version `seq`, None if it wasn't there
+    pub fn get_as_of(&self, key: &[u8], seq: u64) -> Option<LogPointer> {
+        self.writes(key)
+            .into_iter()
+            .take_while(|write| write.pointer.version <= seq)
+            .last()
+            .filter(|write| !write.removed)
+            .map(|write| write.pointer)
+    }
+
+    /// The writes of `key` the history policy keeps, oldest first. Without a policy that is only
+    /// the current one
+    pub fn versions(&self, key: &[u8]) -> Vec<Write> {
+        let now = now();
+        let writes = self.writes(key);
+        let mut kept: Vec<Write> = (0..writes.len())
+            .filter(|i| self.policy_keeps(&writes, *i, now))
+            .map(|i| writes[i])
+            .collect();
+        if kept.len() == 1 && kept[0].removed {
+            kept.clear();
+        }
+        kept
+    }
+
+    /// Every key in `range` that has or had a version, in key order
+    pub fn keys_at<R: RangeBounds<Vec<u8>> + Clone>(&self, range: R) -> BTreeSet<Vec<u8>> {
+        self.keys
+            .range(range.clone())
+            .map(|(key, _)| key)
+            .chain(self.history.range(range).map(|(key, _)| key))
+            .cloned()
+            .collect()
+    }
+
+    /// The replaced writes of every key kept in segments up to `sealed`, oldest first, which a
+    /// merge has to carry over. Drops whatever isn't needed anymore first
+    pub fn history_in(&mut self, sealed: u64) -> Vec<(Vec<u8>, Vec<LogPointer>)> {
+        self.prune_all();
+        self.history
+            .iter()
+            .map(|(key, writes)| {
+                let pointers = writes
+                    .iter()
+                    .map(|write| write.pointer)
+                    .filter(|pointer| pointer.segment <= sealed)
+                    .collect::<Vec<_>>();
+                (key.clone(), pointers)
+            })
+            .filter(|(_, pointers)| !pointers.is_empty())
+            .collect()
+    }
+
+    /// Points a replaced write at where a merge moved it
+    pub fn relocate_history(&mut self, key: &[u8], old: LogPointer, new: LogPointer) {
+        let writes = self.history.get_mut(key).into_iter().flatten();
+        for write in writes.filter(|write| write.pointer == old) {
+            write.pointer = new;
+        }
+    }
+}
+
+/* NOTE:
+ *   The index every handle of a store shares. Readers load the current copy without taking any
+ *   lock and keep reading it even if it is replaced while they do. Writers change a copy of it
+ *   and publish that, which only happens under the writer lock so no change is lost
+ */
+#[derive(Debug)]
+pub(crate) struct SharedIndex {
+    current: ArcSwap<Index>,
+}
+
+impl SharedIndex {
+    pub fn new(index: Index) -> SharedIndex {
+        SharedIndex {
+            current: ArcSwap::from_pointee(index),
+        }
+    }
+
+    pub fn load(&self) -> Guard<Arc<Index>> {
+        self.current.load()
+    }
+
+    /// A copy of the current index to change and then `publish`, the writer lock has to be held
+    /// from here until it is published
+    pub fn edit(&self) -> Index {
+        Index::clone(&self.current.load())
+    }
+
+    pub fn publish(&self, index: Index) {
+        self.current.store(Arc::new(index));
+    }
+
+    /// Whether `index` was replaced since it was loaded
+    pub fn changed_since(&self, index: &Arc<Index>) -> bool {
+        !Arc::ptr_eq(index, &self.current.load())
+    }
+}
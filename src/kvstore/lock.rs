@@ -0,0 +1,58 @@
+use super::error::{KvError, KvResult};
+use std::{
+    fs::{File, TryLockError},
+    io::ErrorKind,
+    path::Path,
+};
+
+// NOTE: Taken in the store directory, whatever the segments are named
+const LOCK_FILE: &str = "LOCK";
+
+/* NOTE:
+ *   An advisory lock on the store directory, held for as long as the store is open. A store
+ *   opened for writing holds it alone, read-only stores share it. The OS lets go of it when the
+ *   process dies, so a crash never leaves the directory locked
+ */
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    _file: Option<File>,
+}
+
+impl DirLock {
+    /// Locks `dir`, failing with `Locked` if a store that conflicts has it open
+    pub fn acquire(dir: &Path, shared: bool) -> KvResult<DirLock> {
+        let path = dir.join(LOCK_FILE);
+        // NOTE: A read-only store changes nothing on disk, so it doesn't create the lock file. If
+        // no store that writes ever opened `dir`, there is no lock to share
+        let opened = if shared {
+            File::open(&path)
+        } else {
+            File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+        };
+        let file = match opened {
+            Ok(file) => file,
+            Err(e) if shared && e.kind() == ErrorKind::NotFound => {
+                return Ok(DirLock { _file: None })
+            }
+            Err(_) => return Err(KvError::OpenError { path }),
+        };
+
+        let locked = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+        match locked {
+            Ok(()) => Ok(DirLock { _file: Some(file) }),
+            Err(TryLockError::WouldBlock) => Err(KvError::Locked {
+                path: dir.to_path_buf(),
+            }),
+            Err(TryLockError::Error(_)) => Err(KvError::OpenError { path }),
+        }
+    }
+}
@@ -1,324 +1,1274 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     error::Error,
     fs::{self, create_dir, File},
-    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    io::{Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
     path::{Path, PathBuf},
-    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
+pub mod batch;
+pub mod blob;
+pub mod cache;
 pub mod command;
+mod compaction;
+pub mod crypto;
 pub mod error;
+pub mod expiry;
+mod flush;
+mod hint;
+mod index;
+mod lock;
+pub mod options;
+pub mod segment;
+pub mod snapshot;
+pub mod transaction;
+use batch::WriteBatch;
+use blob::{open_blob, BlobRef, BlobUsage, BlobWriter, BLOB_MAGIC};
+use cache::{CacheStats, ValueCache};
 use chrono::Local;
 use command::Command;
-use error::{KvError, KvResult};
-use tempfile::TempDir;
+use compaction::{Compactor, Merge};
+use error::{CasResult, CompareAndSwapError, KvError, KvResult};
+use expiry::{expire, expires_at, now, Sweeper, Ttl};
+use flush::{sync_files, Flusher, GroupCommit};
+use hint::read_hint;
+use index::{Index, SharedIndex};
+use lock::DirLock;
+use options::{CompactionPolicy, Compression, KvStoreOptions, SyncPolicy};
+use segment::{
+    is_legacy, open_for_append, upgrade_legacy, DataDir, LogPointer, SegmentFiles, SegmentReader,
+    TailRecovery, SEGMENT_MAGIC,
+};
+use snapshot::Snapshot;
+use transaction::Transaction;
+
+// NOTE: Stores written before segments existed kept everything in a single log.txt
+const LEGACY_LOG: &str = "log.txt";
+
+/// A key and its value
+pub type Pair = (Vec<u8>, Vec<u8>);
 
-// Consts
-// WARNING: FOR BENCHES, change this
-const COMPACTION_THRESHOLD: u64 = 1024;
+/// What a scan returns, in key order
+pub type Scan = std::vec::IntoIter<Pair>;
+
+/// A write of a key kept by the history policy, see `KvStore::get_history`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// In milliseconds since the unix epoch, None if it was written while history wasn't kept
+    pub written_at: Option<u64>,
+    /// None if the key was removed
+    pub value: Option<Vec<u8>>,
+}
 
+/* NOTE:
+ *   Clones share the same index and writer, so a clone can be handed to another thread. Writes go
+ *   through the writer lock, reads take no lock on the store and go through the segment files
+ *   all clones share, and compaction runs in the background on the sealed segments while new
+ *   writes go to a fresh active one
+ */
 #[derive(Debug, Clone)]
 pub struct KvStore {
-    path: PathBuf,
-    pub table: HashMap<String, u64>,
-    compaction_threshold: u64,
+    dir: Arc<DataDir>,
+    index: Arc<SharedIndex>,
+    files: SegmentFiles,
+    cache: Option<Arc<ValueCache>>,
+    writer: Arc<Mutex<Writer>>,
+    compactor: Arc<Compactor>,
+    sweeper: Option<Arc<Sweeper>>,
+    flusher: Option<Arc<Flusher>>,
+    group: Option<Arc<GroupCommit>>,
+    recovered: Option<TailRecovery>,
+    // NOTE: Last so it is only let go of once the background threads above are done
+    lock: Option<Arc<DirLock>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Writer {
+    dir: Arc<DataDir>,
+    options: KvStoreOptions,
+    active: u64,
+    disk_size: u64,
+    // NOTE: Bytes of records that a later Set or Remove made useless, in total and per segment
+    stale_bytes: u64,
+    stale: HashMap<u64, u64>,
+    // NOTE: The last version handed out, see `LogPointer::version`
+    version: u64,
+    // NOTE: Bytes written to each segment and blob file since it was last flushed to the disk
+    unsynced: HashMap<PathBuf, u64>,
+    cache: Option<Arc<ValueCache>>,
+    // NOTE: The blob file large values are appended to, the id the next one gets and how much of
+    // every blob file is garbage
+    blob: BlobWriter,
+    next_blob: u64,
+    blobs: BTreeMap<u64, BlobUsage>,
+}
+
+impl Writer {
+    /// Appends the command to the active segment, sealing it once it is full
+    fn append(&mut self, cmd: &Command) -> KvResult<LogPointer> {
+        let pointers = self.append_all(std::slice::from_ref(cmd))?;
+        Ok(pointers[0])
+    }
+
+    /// Appends a batch between its begin and commit markers, returning where each of its
+    /// commands went
+    fn append_batch(&mut self, cmds: &[Command]) -> KvResult<Vec<LogPointer>> {
+        let mut records = Vec::with_capacity(cmds.len() + 2);
+        records.push(Command::BatchBegin {
+            count: cmds.len() as u32,
+        });
+        records.extend_from_slice(cmds);
+        records.push(Command::BatchCommit);
+
+        let mut pointers = self.append_all(&records)?;
+
+        // NOTE: The markers are only needed until the batch is merged
+        let commit = pointers.pop().unwrap();
+        let begin = pointers.remove(0);
+        self.mark_stale(begin);
+        self.mark_stale(commit);
+
+        Ok(pointers)
+    }
+
+    /// Appends the commands with a single write, all to the same segment
+    fn append_all(&mut self, cmds: &[Command]) -> KvResult<Vec<LogPointer>> {
+        if self.options.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        let (mut f, created) = open_for_append(&self.dir.segment(self.active))?;
+        if created {
+            self.disk_size += SEGMENT_MAGIC.len() as u64;
+        }
+        let start = f.seek(SeekFrom::End(0)).map_err(|_| KvError::WriteError)?;
+
+        // NOTE: Records are only stamped while history is kept, a batch all gets the same time
+        let written_at = Some(now()).filter(|_| self.options.history.is_enabled());
+        let mut buf = Vec::new();
+        let mut pointers = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let blob = match self.write_blob(cmd) {
+                Ok(blob) => blob,
+                Err(e) => {
+                    self.drop_blobs(&pointers);
+                    return Err(e);
+                }
+            };
+            let encoded = match blob {
+                Some(blob) => cmd
+                    .with_blob(blob)
+                    .encode_with(written_at, Compression::None),
+                None => cmd.encode_with(written_at, self.options.compression),
+            };
+            let record = self.dir.encrypt(encoded);
+            let offset = start + buf.len() as u64;
+            self.version += 1;
+            pointers.push(
+                LogPointer::new(self.active, offset, record.len() as u64)
+                    .with_expiry(cmd.expires_at())
+                    .with_version(self.version)
+                    .with_written_at(written_at)
+                    .with_blob(blob),
+            );
+            buf.extend_from_slice(&record);
+        }
+
+        if f.write_all(&buf).is_err() {
+            // NOTE: Don't leave half a write behind for the next one to be appended after
+            let _ = f.set_len(start);
+            self.drop_blobs(&pointers);
+            return Err(KvError::WriteError);
+        }
+        // NOTE: Even with `SyncPolicy::Always` the flush is left to `GroupCommit`, after the lock
+        *self
+            .unsynced
+            .entry(self.dir.segment(self.active))
+            .or_default() += buf.len() as u64;
+
+        let end = start + buf.len() as u64;
+        self.disk_size += end - start;
+        if end >= self.options.segment_size {
+            self.active += 1;
+        }
+
+        Ok(pointers)
+    }
+
+    /// Writes the value of a set to the active blob file if it is big enough to go there
+    fn write_blob(&mut self, cmd: &Command) -> KvResult<Option<BlobRef>> {
+        let threshold = self.options.blob_threshold;
+        let (key, val) = match cmd {
+            Command::Set { key, val, .. } if threshold > 0 && val.len() >= threshold => (key, val),
+            _ => return Ok(None),
+        };
+
+        if self.blob.len() >= self.options.segment_size {
+            self.seal_blob();
+        }
+        let blob = self.blob.append(self.dir.keys(), key, val)?;
+        let usage = self.blobs.entry(blob.file).or_default();
+        usage.size = self.blob.len();
+        usage.sealed = self.dir.encrypts();
+        *self
+            .unsynced
+            .entry(self.blob.path().to_path_buf())
+            .or_default() += blob.len;
+
+        Ok(Some(blob))
+    }
+
+    /// Starts a new blob file, if anything was written to the active one
+    fn seal_blob(&mut self) {
+        if self.blob.len() > 0 {
+            self.blob = BlobWriter::new(self.next_blob, self.dir.blob(self.next_blob));
+            self.next_blob += 1;
+        }
+    }
+
+    /// Counts the values written for a write that failed as garbage
+    fn drop_blobs(&mut self, pointers: &[LogPointer]) {
+        for blob in pointers.iter().filter_map(|pointer| pointer.blob) {
+            self.mark_blob_stale(blob);
+        }
+    }
+
+    /// The sealed blob files the next merge should rewrite: the ones that are mostly garbage, or
+    /// with `all` every one holding any garbage at all or not encrypted with the current key
+    fn blob_victims(&self, all: bool) -> Vec<u64> {
+        // NOTE: Rewriting the values is also what rotates the encryption key
+        let rekey = !self.options.old_keys.is_empty();
+        self.blobs
+            .iter()
+            .filter(|(id, _)| **id != self.blob.id())
+            .filter(|(_, usage)| match all {
+                true => usage.stale > 0 || rekey || usage.sealed != self.dir.encrypts(),
+                false => usage.is_garbage(),
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Flushes every segment and blob file written to since the last flush to the disk
+    fn flush(&mut self) -> KvResult<()> {
+        sync_files(&self.unsynced)?;
+        self.unsynced.clear();
+
+        Ok(())
+    }
+
+    /// Hands the files written to since the last flush over to be flushed without the lock,
+    /// along with the last version they hold
+    fn take_unsynced(&mut self) -> (u64, HashMap<PathBuf, u64>) {
+        (self.version, std::mem::take(&mut self.unsynced))
+    }
+
+    /// Puts back what `take_unsynced` took after the flush failed
+    fn restore_unsynced(&mut self, unsynced: HashMap<PathBuf, u64>) {
+        for (path, bytes) in unsynced {
+            *self.unsynced.entry(path).or_default() += bytes;
+        }
+    }
+
+    fn mark_stale(&mut self, pointer: LogPointer) {
+        *self.stale.entry(pointer.segment).or_default() += pointer.len;
+        self.stale_bytes += pointer.len;
+        if let Some(blob) = pointer.blob {
+            self.mark_blob_stale(blob);
+        }
+        if let Some(cache) = &self.cache {
+            cache.remove(&pointer);
+        }
+    }
+
+    fn mark_blob_stale(&mut self, blob: BlobRef) {
+        // NOTE: Nothing to count once a merge has removed the file
+        if let Some(usage) = self.blobs.get_mut(&blob.file) {
+            usage.stale += blob.len;
+        }
+    }
+
+    /// Forgets the stale bytes of every segment up to `sealed` once a merge has dropped them
+    fn clear_stale(&mut self, sealed: u64) {
+        let dropped: u64 = self
+            .stale
+            .iter()
+            .filter(|(id, _)| **id <= sealed)
+            .map(|(_, len)| len)
+            .sum();
+        self.stale.retain(|id, _| *id > sealed);
+        self.stale_bytes -= dropped;
+        if let Some(cache) = &self.cache {
+            cache.remove_segments(sealed);
+        }
+    }
+
+    fn wants_compaction(&self) -> bool {
+        if self.options.read_only {
+            return false;
+        }
+        match self.options.compaction {
+            CompactionPolicy::Size(limit) => self.disk_size > limit || self.wants_blob_gc(),
+            CompactionPolicy::StaleRatio { ratio, min_size } => {
+                self.disk_size > min_size && self.stale_bytes as f64 / self.disk_size as f64 > ratio
+                    || self.wants_blob_gc()
+            }
+            CompactionPolicy::Disabled => false,
+        }
+    }
+
+    /// Whether a blob file is mostly garbage, which only a merge rewrites. That is cheap however
+    /// small the log is, since the log only holds where the values are
+    fn wants_blob_gc(&self) -> bool {
+        !self.blob_victims(false).is_empty()
+    }
+}
+
+/// The version of `key` that readers see right now, None if it isn't there
+fn visible_version(index: &Index, key: &[u8], now: u64) -> Option<u64> {
+    index
+        .get(key)
+        .filter(|pointer| !pointer.is_expired(now))
+        .map(|pointer| pointer.version)
+}
+
+/// Reads a counter, which is kept as decimal text so it can be read like any other value
+pub(crate) fn parse_integer(val: &[u8]) -> KvResult<i64> {
+    std::str::from_utf8(val)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .ok_or(KvError::NotAnInteger)
+}
+
+/// Whether `range` starts after it ends, which `BTreeMap::range` would panic on
+pub(crate) fn is_backwards(range: &impl RangeBounds<Vec<u8>>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start > end,
+        _ => false,
+    }
+}
+
+/// Updates the index for a command that was just written or read back from disk
+fn apply(index: &mut Index, writer: &mut Writer, cmd: Command, pointer: LogPointer, now: u64) {
+    match cmd {
+        Command::Set { key, .. } | Command::SetBlob { key, .. } => {
+            replay_set(index, writer, key, pointer, now)
+        }
+        Command::Remove { key } => {
+            writer.mark_stale(pointer);
+            if let Some(old) = index.remove(&key, pointer) {
+                writer.mark_stale(old);
+            }
+        }
+        Command::BatchBegin { .. } | Command::BatchCommit => writer.mark_stale(pointer),
+    }
+}
+
+/// A batch read back from disk that hasn't reached its commit marker yet, as how many commands
+/// it should hold, where its begin marker is and the commands read so far
+type OpenBatch = (u32, LogPointer, Vec<(Command, LogPointer)>);
+
+/// Counts a batch that is never going to be applied as garbage
+fn discard(writer: &mut Writer, begin: LogPointer, ops: Vec<(Command, LogPointer)>) {
+    writer.mark_stale(begin);
+    for (_, pointer) in ops {
+        writer.mark_stale(pointer);
+    }
+}
+
+/// Puts a set read back from disk into the index, one that expired while the store was closed
+/// counts as removed unless history is kept, then the sweeper writes its removal
+fn replay_set(index: &mut Index, writer: &mut Writer, key: Vec<u8>, pointer: LogPointer, now: u64) {
+    let old = if pointer.is_expired(now) && !index.keeps_history() {
+        writer.mark_stale(pointer);
+        index.remove(&key, pointer)
+    } else {
+        index.insert(key, pointer)
+    };
+
+    if let Some(old) = old {
+        writer.mark_stale(old);
+    }
 }
 
 impl KvStore {
     pub fn new(path: PathBuf) -> KvStore {
+        KvStore::with_options(path, KvStoreOptions::default())
+    }
+
+    fn with_options(path: PathBuf, options: KvStoreOptions) -> KvStore {
+        let dir = Arc::new(
+            DataDir::new(path, options.data_file_name.clone()).with_keys(options.keyring()),
+        );
+        let index = Index::with_history(options.history);
+        let group =
+            Some(Arc::new(GroupCommit::default())).filter(|_| options.sync == SyncPolicy::Always);
+        let cache = Some(options.cache_size)
+            .filter(|size| *size > 0)
+            .map(|size| Arc::new(ValueCache::new(size)));
+        let files = SegmentFiles::new(options.read_mode);
         KvStore {
-            path,
-            table: HashMap::new(),
-            compaction_threshold: COMPACTION_THRESHOLD,
+            writer: Arc::new(Mutex::new(Writer {
+                dir: Arc::clone(&dir),
+                options,
+                active: 1,
+                disk_size: 0,
+                stale_bytes: 0,
+                stale: HashMap::new(),
+                version: 0,
+                unsynced: HashMap::new(),
+                cache: cache.clone(),
+                blob: BlobWriter::new(1, dir.blob(1)),
+                next_blob: 2,
+                blobs: BTreeMap::new(),
+            })),
+            dir,
+            index: Arc::new(SharedIndex::new(index)),
+            files,
+            cache,
+            compactor: Arc::new(Compactor::default()),
+            sweeper: None,
+            flusher: None,
+            group,
+            recovered: None,
+            lock: None,
         }
     }
+
+    /// The torn record `open` dropped from the end of the log, if the last shutdown was unclean
+    pub fn recovered(&self) -> Option<&TailRecovery> {
+        self.recovered.as_ref()
+    }
+
     pub fn nocompactionset(&mut self, key: String, val: String) -> KvResult<()> {
-        let cmd = Command::set(key.clone(), val.clone());
+        self.append_set(Command::set(key.into_bytes(), val.into_bytes()))
+    }
+
+    fn append_set(&mut self, cmd: Command) -> KvResult<()> {
+        let key = match &cmd {
+            Command::Set { key, .. } => key.clone(),
+            _ => return Err(KvError::WriteError),
+        };
+
+        let version = {
+            let mut writer = self.writer.lock().unwrap();
+            let pointer = writer.append(&cmd)?;
+            let mut index = self.index.edit();
+            if let Some(old) = index.insert(key, pointer) {
+                writer.mark_stale(old);
+            }
+            self.index.publish(index);
+            writer.version
+        };
+
+        self.wait_durable(version)
+    }
 
-        let mut f = File::options()
-            .read(true)
-            .append(true)
-            .open(&self.path)
-            .unwrap();
+    /// Waits for the writes up to `version` to be on the disk with `SyncPolicy::Always`, see
+    /// `GroupCommit`. Called once the writer lock is let go so other writers can join the flush
+    fn wait_durable(&self, version: u64) -> KvResult<()> {
+        match &self.group {
+            Some(group) => group.wait(&self.writer, version),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes every set and remove in `batch` at once. Readers never see part of a batch, and
+    /// after a crash either all of it is there on open or none of it is
+    pub fn write(&mut self, batch: WriteBatch) -> KvResult<()> {
+        self.commit(&BTreeMap::new(), batch)
+    }
+
+    /// Pins a read-only view of the store as it is now, see `Snapshot`
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.clone())
+    }
+
+    /// Starts a transaction, see `Transaction`
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.clone())
+    }
 
-        let start_pos = f.seek(SeekFrom::End(0)).unwrap();
-        let _ = serde_json::to_writer(&mut f, &cmd);
-        let _ = f.write_all(b"\n");
-        self.table.insert(key, start_pos);
+    /// Writes `batch` only if every key in `reads` is still at the version it was read at, None
+    /// meaning the key wasn't there
+    pub(crate) fn commit(
+        &mut self,
+        reads: &BTreeMap<Vec<u8>, Option<u64>>,
+        batch: WriteBatch,
+    ) -> KvResult<()> {
+        let version = {
+            // NOTE: Every write takes the writer lock first, so nothing can change between the
+            // check and the write
+            let now = now();
+            let mut writer = self.writer.lock().unwrap();
+            {
+                let index = self.index.load();
+                for (key, version) in reads {
+                    if visible_version(&index, key, now) != *version {
+                        return Err(KvError::TransactionConflict);
+                    }
+                }
+            }
+
+            if batch.is_empty() {
+                return Ok(());
+            }
+
+            let ops = batch.into_ops();
+            let pointers = writer.append_batch(&ops)?;
+
+            let mut index = self.index.edit();
+            for (cmd, pointer) in ops.into_iter().zip(pointers) {
+                apply(&mut index, &mut writer, cmd, pointer, now);
+            }
+            self.index.publish(index);
+            writer.version
+        };
+        self.wait_durable(version)?;
+        self.maybe_compact();
 
         Ok(())
     }
 
     pub fn set(&mut self, key: String, val: String) -> KvResult<()> {
-        let cmd = Command::set(key.clone(), val.clone());
+        self.set_bytes(key.into_bytes(), val.into_bytes())
+    }
 
-        let mut f = File::options()
-            .read(true)
-            .append(true)
-            .open(&self.path)
-            .unwrap();
+    /// Same as `set`, for keys and values that aren't text
+    pub fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) -> KvResult<()> {
+        self.append_set(Command::set(key, val))?;
+        self.maybe_compact();
 
-        let start_pos = f.seek(SeekFrom::End(0)).unwrap();
-        let _ = serde_json::to_writer(&mut f, &cmd);
-        let _ = f.write_all(b"\n");
-        self.table.insert(key, start_pos);
+        Ok(())
+    }
 
-        let size = fs::metadata(&self.path);
+    /// Same as `set`, but the key is removed once `ttl` has passed
+    pub fn set_with_ttl(&mut self, key: String, val: String, ttl: Duration) -> KvResult<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), val.into_bytes(), ttl)
+    }
 
-        let length = size
-            .expect("Isnt able to extract the size from the lenght!!!")
-            .len();
-
-        if length > self.compaction_threshold {
-            let _ = self.compaction();
-        }
+    pub fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> KvResult<()> {
+        self.append_set(Command::set_expiring(key, val, expires_at(ttl)))?;
+        self.maybe_compact();
 
         Ok(())
     }
 
     pub fn set_bench_specific(&mut self, key: String, val: String) -> Result<(), Box<dyn Error>> {
-        let cmd = Command::set(key.clone(), val.clone());
+        Ok(self.nocompactionset(key, val)?)
+    }
 
-        // Part 1: Make the open operation atomic and idempotent.
-        let mut f = File::options()
-            .create(true) // Create the file if it doesn't exist.
-            .append(true) // Always write to the end.
-            .open(&self.path)?; // Part 2: Use `?` to propagate errors, NOT .unwrap().
+    /// Fails with `ParseError` if the value isn't valid UTF-8, use `get_bytes` for those
+    pub fn get(&self, key: String) -> KvResult<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(val) => Ok(Some(
+                String::from_utf8(val).map_err(|_| KvError::ParseError)?,
+            )),
+            None => Ok(None),
+        }
+    }
 
-        // Part 2 (continued): Propagate errors for ALL I/O operations.
-        let start_pos = f.seek(SeekFrom::End(0))?;
+    pub fn get_bytes(&self, key: &[u8]) -> KvResult<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(val, _)| val))
+    }
 
-        // The to_writer can fail, so we map its specific error to our KvError type.
-        serde_json::to_writer(&mut f, &cmd).map_err(|_| KvError::WriteError)?;
+    /// Same as `get_bytes`, along with the version of the key
+    pub(crate) fn get_versioned(&self, key: &[u8]) -> KvResult<Option<(Vec<u8>, u64)>> {
+        self.read_index(|index| {
+            let pointer = match index.get(key) {
+                Some(pointer) => *pointer,
+                None => return Ok(None),
+            };
 
-        f.write_all(b"\n")?;
+            if pointer.is_expired(now()) {
+                expire(&self.writer, &self.index, vec![(key.to_vec(), pointer)])?;
+                return Ok(None);
+            }
 
-        self.table.insert(key, start_pos);
+            Ok(self.read_value(pointer)?.map(|val| (val, pointer.version)))
+        })
+    }
 
-        Ok(())
+    /// Runs `read` on the current index. A merge can remove a segment the index points into
+    /// while `read` is going, then it runs again on the index the merge left behind
+    pub(crate) fn read_index<T>(&self, read: impl Fn(&Index) -> KvResult<T>) -> KvResult<T> {
+        loop {
+            let index = self.index.load();
+            match read(&index) {
+                Err(KvError::OpenError { .. }) if self.index.changed_since(&index) => continue,
+                res => return res,
+            }
+        }
     }
-    pub fn get(&self, key: String) -> KvResult<Option<String>> {
-        let val = self.table.get(&key);
-        match &val {
-            Some(_) => (),
-            None => return Ok(None),
-        }
-
-        let file = File::options().read(true).open(&self.path).unwrap();
-
-        let mut f = BufReader::new(file);
-
-        // Seek from val to the \n
-        let _ = f.seek(SeekFrom::Start(
-            *val.expect("Isnt able to seek from val to \\n"),
-        ));
-        let mut line = String::new();
-        let _ = f.read_line(&mut line);
-        let res = serde_json::from_str::<Command>(&line.to_string());
-        match res {
-            Ok(re) => match re {
-                Command::Set { key: _, val } => Ok(Some(val)),
-                _ => Ok(None),
-            },
-            Err(_) => Err(KvError::ParseError),
+
+    fn read_value(&self, pointer: LogPointer) -> KvResult<Option<Vec<u8>>> {
+        if let Some(val) = self.cache.as_ref().and_then(|cache| cache.get(&pointer)) {
+            return Ok(Some(val));
         }
+
+        let val = match Command::decode(&self.files.read_record(&self.dir, pointer)?)? {
+            Command::Set { val, .. } => val,
+            Command::SetBlob { key, blob, .. } => self.files.read_blob(&self.dir, &key, blob)?,
+            _ => return Ok(None),
+        };
+        if let Some(cache) = &self.cache {
+            cache.insert(&pointer, &val);
+        }
+        Ok(Some(val))
     }
 
-    pub fn remove(&mut self, key: String) -> KvResult<()> {
-        let cmd = Command::rm(key.clone());
+    /// Every version of `key` the history policy keeps, newest first. Without a policy that is
+    /// only the current value, see `KvStoreOptions::history`
+    pub fn get_history(&self, key: String) -> KvResult<Vec<Version>> {
+        self.get_history_bytes(key.as_bytes())
+    }
 
-        let mut f = File::options()
-            .read(true)
-            .append(true)
-            .open(&self.path)
-            .unwrap();
+    pub fn get_history_bytes(&self, key: &[u8]) -> KvResult<Vec<Version>> {
+        self.read_index(|index| {
+            let mut versions = Vec::new();
+            for write in index.versions(key).into_iter().rev() {
+                let value = match write.removed {
+                    true => None,
+                    false => self.read_value(write.pointer)?,
+                };
+                versions.push(Version {
+                    written_at: write.pointer.written_at,
+                    value,
+                });
+            }
+
+            Ok(versions)
+        })
+    }
 
-        let _ = serde_json::to_writer(&mut f, &cmd);
-        let _ = f.write_all(b"\n");
-        match self.table.remove(&key) {
-            Some(_) => Ok(()),
-            None => Err(KvError::RemoveError),
+    /// The value `key` held at `timestamp`, in milliseconds since the unix epoch, as far back as
+    /// the history policy keeps. Fails with `ParseError` if the value isn't valid UTF-8
+    pub fn get_at(&self, key: String, timestamp: u64) -> KvResult<Option<String>> {
+        match self.get_at_bytes(key.as_bytes(), timestamp)? {
+            Some(val) => Ok(Some(
+                String::from_utf8(val).map_err(|_| KvError::ParseError)?,
+            )),
+            None => Ok(None),
         }
     }
 
-    pub fn open(path: impl Into<PathBuf> + AsRef<Path> + Copy) -> KvResult<KvStore> {
-        let f = match File::open(path.into().join("log.txt")) {
-            Ok(f) => f,
-            Err(_) => {
-                let _ = File::create(path.into().join("log.txt"));
-                File::open(path.into().join("log.txt")).unwrap()
+    pub fn get_at_bytes(&self, key: &[u8], timestamp: u64) -> KvResult<Option<Vec<u8>>> {
+        self.read_index(|index| {
+            // NOTE: Records that weren't stamped were written before any that were
+            let write = index
+                .versions(key)
+                .into_iter()
+                .take_while(|write| write.pointer.written_at.unwrap_or(0) <= timestamp)
+                .last();
+            match write {
+                Some(write) if !write.removed && !write.pointer.is_expired(timestamp) => {
+                    self.read_value(write.pointer)
+                }
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// How long `key` has left to live, None if it isn't there
+    pub fn ttl(&self, key: String) -> KvResult<Option<Ttl>> {
+        self.ttl_bytes(key.as_bytes())
+    }
+
+    pub fn ttl_bytes(&self, key: &[u8]) -> KvResult<Option<Ttl>> {
+        let now = now();
+        Ok(self
+            .index
+            .load()
+            .get(key)
+            .filter(|pointer| !pointer.is_expired(now))
+            .map(|pointer| Ttl::of(pointer, now)))
+    }
+
+    /// Every key in `range` with its value. The values are read up front, so the scan doesn't
+    /// change under you when keys are written while it is iterated
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> KvResult<Scan> {
+        if is_backwards(&range) {
+            return Ok(Vec::new().into_iter());
+        }
+
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.read_index(|index| self.read_pairs(index.range(bounds.clone())))
+    }
+
+    /// Every key starting with `prefix` with its value, see `scan`
+    pub fn scan_prefix(&self, prefix: &[u8]) -> KvResult<Scan> {
+        self.read_index(|index| {
+            let entries = index
+                .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix));
+            self.read_pairs(entries)
+        })
+    }
+
+    fn read_pairs<'a>(
+        &self,
+        entries: impl Iterator<Item = (&'a Vec<u8>, &'a LogPointer)>,
+    ) -> KvResult<Scan> {
+        let now = now();
+        let mut pairs = Vec::new();
+        for (key, pointer) in entries.filter(|(_, pointer)| !pointer.is_expired(now)) {
+            if let Some(val) = self.read_value(*pointer)? {
+                pairs.push((key.clone(), val));
+            }
+        }
+
+        Ok(pairs.into_iter())
+    }
+
+    pub fn remove(&mut self, key: String) -> KvResult<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> KvResult<()> {
+        let version = {
+            let mut writer = self.writer.lock().unwrap();
+            let now = now();
+            match self.index.load().get(key) {
+                Some(pointer) if !pointer.is_expired(now) => (),
+                _ => return Err(KvError::RemoveError),
             }
+
+            let cmd = Command::rm(key.to_vec());
+            let pointer = writer.append(&cmd)?;
+            let mut index = self.index.edit();
+            if let Some(old) = index.remove(key, pointer) {
+                writer.mark_stale(old);
+            }
+            self.index.publish(index);
+            // NOTE: A tombstone is garbage too, the next merge drops it with the record it hides
+            writer.mark_stale(pointer);
+            writer.version
         };
-        let mut hash: HashMap<String, u64> = HashMap::new();
-        let mut buffer = BufReader::new(&f);
-        let mut pos = buffer.seek(SeekFrom::Start(0)).unwrap();
 
-        loop {
-            let mut line = String::new();
+        self.wait_durable(version)
+    }
 
-            let length = buffer
-                .read_line(&mut line)
-                .expect("Isnt able to read a line in the log.txt!!!");
-            if length == 0 {
-                break;
+    /// Writes `new` only if `key` holds `expected`, None meaning the key isn't there on either
+    /// side. Otherwise nothing is written and the value the key holds is handed back
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KvResult<CasResult> {
+        let version = {
+            let now = now();
+            let mut writer = self.writer.lock().unwrap();
+            let current = {
+                // NOTE: A merge only swaps the index under the writer lock, so every segment this
+                // one points into is still there
+                let index = self.index.load();
+                match index.get(&key).filter(|pointer| !pointer.is_expired(now)) {
+                    Some(pointer) => self.read_value(*pointer)?,
+                    None => None,
+                }
+            };
+
+            if current != expected {
+                return Ok(Err(CompareAndSwapError {
+                    current,
+                    proposed: new,
+                }));
             }
-            let res = serde_json::from_str::<Command>(&line.to_string());
-
-            match res {
-                Ok(re) => {
-                    match re {
-                        Command::Set { key, val: _ } => hash.insert(key, pos),
-                        Command::Remove { key } => hash.remove(&key),
-                    };
+
+            let cmd = match new {
+                Some(val) => Command::set(key, val),
+                None if current.is_some() => Command::rm(key),
+                None => return Ok(Ok(())),
+            };
+            let pointer = writer.append(&cmd)?;
+            let mut index = self.index.edit();
+            apply(&mut index, &mut writer, cmd, pointer, now);
+            self.index.publish(index);
+            writer.version
+        };
+        self.wait_durable(version)?;
+        self.maybe_compact();
+
+        Ok(Ok(()))
+    }
+
+    /// Sets `key` only if it isn't there, see `compare_and_swap`
+    pub fn set_if_absent(&mut self, key: Vec<u8>, val: Vec<u8>) -> KvResult<CasResult> {
+        self.compare_and_swap(key, None, Some(val))
+    }
+
+    /// Removes `key` only if it holds `expected`, see `compare_and_swap`
+    pub fn remove_if_equals(&mut self, key: Vec<u8>, expected: Vec<u8>) -> KvResult<CasResult> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// Adds `delta` to the integer `key` holds, starting from 0 if it isn't there, and returns
+    /// the new value. Fails with `NotAnInteger` if the value isn't one and `Overflow` if the sum
+    /// doesn't fit in an i64
+    pub fn incr(&mut self, key: String, delta: i64) -> KvResult<i64> {
+        self.incr_bytes(key.into_bytes(), delta)
+    }
+
+    /// Same as `incr` with `-delta`
+    pub fn decr(&mut self, key: String, delta: i64) -> KvResult<i64> {
+        self.incr_bytes(
+            key.into_bytes(),
+            delta.checked_neg().ok_or(KvError::Overflow)?,
+        )
+    }
+
+    pub fn incr_bytes(&mut self, key: Vec<u8>, delta: i64) -> KvResult<i64> {
+        let (new, version) = {
+            let now = now();
+            let mut writer = self.writer.lock().unwrap();
+            let (current, expires_at) = {
+                let index = self.index.load();
+                match index.get(&key).filter(|pointer| !pointer.is_expired(now)) {
+                    Some(pointer) => (self.read_value(*pointer)?, pointer.expires_at),
+                    None => (None, None),
                 }
+            };
+
+            let current = match current {
+                Some(val) => parse_integer(&val)?,
+                None => 0,
+            };
+            let new = current.checked_add(delta).ok_or(KvError::Overflow)?;
+
+            // NOTE: Counting keeps the TTL the key was set with
+            let val = new.to_string().into_bytes();
+            let cmd = match expires_at {
+                Some(at) => Command::set_expiring(key, val, at),
+                None => Command::set(key, val),
+            };
+            let pointer = writer.append(&cmd)?;
+            let mut index = self.index.edit();
+            apply(&mut index, &mut writer, cmd, pointer, now);
+            self.index.publish(index);
+            (new, writer.version)
+        };
+        self.wait_durable(version)?;
+        self.maybe_compact();
+
+        Ok(new)
+    }
+
+    pub fn open(path: impl Into<PathBuf> + AsRef<Path> + Copy) -> KvResult<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens the store without automatic compaction
+    pub fn open_custom(path: impl Into<PathBuf> + AsRef<Path> + Copy) -> KvResult<KvStore> {
+        KvStore::open_with(
+            path,
+            KvStoreOptions::new().compaction(CompactionPolicy::Disabled),
+        )
+    }
+
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> KvResult<KvStore> {
+        options.check_data_file_name()?;
+        let read_only = options.read_only;
+        let mut store = KvStore::with_options(path.into(), options);
+        store.lock = Some(Arc::new(DirLock::acquire(store.dir.path(), read_only)?));
 
-                Err(_) => return Err(KvError::ParseError),
+        let legacy = store.dir.path().join(LEGACY_LOG);
+        if legacy.exists() && store.dir.segment_ids()?.is_empty() {
+            if read_only {
+                return Err(KvError::ReadOnly);
             }
+            fs::rename(&legacy, store.dir.segment(1))
+                .map_err(|_| KvError::OpenError { path: legacy })?;
+        }
 
-            pos = buffer.seek(SeekFrom::Start(pos + length as u64)).unwrap();
+        store.build_index()?;
+        if read_only {
+            return Ok(store);
         }
 
-        Ok(KvStore {
-            path: path.into().join("log.txt"),
-            table: hash,
-            compaction_threshold: COMPACTION_THRESHOLD,
-        })
+        let interval = store.writer.lock().unwrap().options.sweep_interval;
+        store.sweeper = Some(Arc::new(Sweeper::start(
+            Arc::clone(&store.writer),
+            Arc::clone(&store.index),
+            interval,
+        )));
+
+        if let SyncPolicy::Periodic(interval) = store.writer.lock().unwrap().options.sync {
+            store.flusher = Some(Arc::new(Flusher::start(
+                Arc::clone(&store.writer),
+                interval,
+            )));
+        }
+
+        Ok(store)
     }
 
-    pub fn open_custom(path: impl Into<PathBuf> + AsRef<Path> + Copy) -> KvResult<KvStore> {
-        let compaction_threshold: u64 = 10000000000000000;
-        let f = match File::open(path.into().join("log.txt")) {
-            Ok(f) => f,
-            Err(_) => {
-                let _ = File::create(path.into().join("log.txt"));
-                File::open(path.into().join("log.txt")).unwrap()
+    /// Rebuilds the index from every segment, oldest to newest. Segments a merge wrote are
+    /// loaded from their hint, only the rest are replayed record by record
+    fn build_index(&mut self) -> KvResult<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut index = self.index.edit();
+        index.clear();
+        writer.disk_size = 0;
+        writer.stale_bytes = 0;
+        writer.stale.clear();
+        writer.unsynced.clear();
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        self.recovered = None;
+        let read_only = writer.options.read_only;
+
+        // NOTE: Merges that never finished, the segments they were merging are still here
+        if !read_only {
+            self.dir.remove_unfinished_merges()?;
+        }
+
+        let now = now();
+        let ids = self.dir.segment_ids()?;
+        let mut hinted = false;
+        for id in &ids {
+            hinted = false;
+            if let Some(entries) = read_hint(&self.dir, *id) {
+                for (key, pointer) in entries {
+                    replay_set(&mut index, &mut writer, key, pointer, now);
+                }
+                writer.disk_size += self.dir.segment_len(*id);
+                hinted = true;
+                continue;
             }
-        };
-        let mut hash: HashMap<String, u64> = HashMap::new();
-        let mut buffer = BufReader::new(&f);
-        let mut pos = buffer.seek(SeekFrom::Start(0)).unwrap();
 
-        loop {
-            let mut line = String::new();
+            // NOTE: Only the newest segment can have been cut off by a crash, a bad record
+            // anywhere else is real corruption
+            let is_tail = Some(id) == ids.last();
 
-            let length = buffer
-                .read_line(&mut line)
-                .expect("Isnt able to read the line in log.txt");
-            if length == 0 {
-                break;
+            let path = self.dir.segment(*id);
+            if is_legacy(&path)? {
+                if read_only {
+                    return Err(KvError::ReadOnly);
+                }
+                self.recovered = upgrade_legacy(&path, is_tail)?;
             }
-            let res = serde_json::from_str::<Command>(&line.to_string());
-
-            match res {
-                Ok(re) => {
-                    match re {
-                        Command::Set { key, val: _ } => hash.insert(key, pos),
-                        Command::Remove { key } => hash.remove(&key),
-                    };
+
+            let mut reader = SegmentReader::open(&self.dir, *id)?;
+            // NOTE: The commands of a batch are held back until its commit marker shows up
+            let mut batch: Option<OpenBatch> = None;
+            let torn = loop {
+                match reader.next_record() {
+                    Ok(Some((Command::BatchBegin { count }, pointer))) => {
+                        // NOTE: A begin marker without a commit before the next one is a batch
+                        // that failed to write
+                        if let Some((_, begin, ops)) = batch.replace((count, pointer, Vec::new())) {
+                            discard(&mut writer, begin, ops);
+                        }
+                    }
+                    Ok(Some((Command::BatchCommit, pointer))) => {
+                        writer.mark_stale(pointer);
+                        match batch.take() {
+                            Some((count, begin, ops)) if ops.len() == count as usize => {
+                                writer.mark_stale(begin);
+                                for (cmd, pointer) in ops {
+                                    apply(&mut index, &mut writer, cmd, pointer, now);
+                                }
+                            }
+                            Some((_, begin, ops)) => discard(&mut writer, begin, ops),
+                            None => (),
+                        }
+                    }
+                    Ok(Some((cmd, pointer))) => match &mut batch {
+                        Some((_, _, ops)) => ops.push((cmd, pointer)),
+                        None => apply(&mut index, &mut writer, cmd, pointer, now),
+                    },
+                    Ok(None) => break false,
+                    Err(KvError::CorruptLog { .. }) if is_tail && reader.torn() => break true,
+                    Err(e) => return Err(e),
+                }
+            };
+
+            // NOTE: A batch still missing its commit at the end of the log was cut off by a crash,
+            // so the log is cut back to where it began
+            let end = match batch {
+                Some((_, begin, _)) if is_tail => begin.offset,
+                Some((_, begin, ops)) => {
+                    discard(&mut writer, begin, ops);
+                    reader.pos()
                 }
+                None => reader.pos(),
+            };
 
-                Err(_) => return Err(KvError::ParseError),
+            writer.disk_size += end;
+            // NOTE: A read-only store only leaves the torn write out of the index
+            if torn || end < reader.pos() {
+                self.recovered = Some(if read_only {
+                    reader.tail(end)
+                } else {
+                    reader.truncate(end)?
+                });
             }
+        }
 
-            pos = buffer.seek(SeekFrom::Start(pos + length as u64)).unwrap();
+        // NOTE: Keep appending to the newest segment until it fills up, unless a merge wrote it
+        // since appending would throw its hint away
+        writer.active = ids.last().copied().unwrap_or(1);
+        if hinted || self.dir.segment_len(writer.active) >= writer.options.segment_size {
+            writer.active += 1;
         }
 
-        Ok(KvStore {
-            path: path.into().join("log.txt"),
-            table: hash,
-            compaction_threshold,
-        })
+        // NOTE: Counted from what the index points at, a merge cut off by a crash can leave a
+        // blob file behind that nothing points at. New values go to a new blob file
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for blob in index.blobs() {
+            *live.entry(blob.file).or_default() += blob.len;
+        }
+        let blob_ids = self.dir.blob_ids()?;
+        writer.blobs = blob_ids
+            .iter()
+            .map(|id| {
+                let size = self.dir.blob_len(*id);
+                let values = size.saturating_sub(BLOB_MAGIC.len() as u64);
+                let stale = values.saturating_sub(live.get(id).copied().unwrap_or(0));
+                let sealed = open_blob(&self.dir.blob(*id)).is_ok_and(|(_, sealed)| sealed);
+                (
+                    *id,
+                    BlobUsage {
+                        size,
+                        stale,
+                        sealed,
+                    },
+                )
+            })
+            .collect();
+        let next = blob_ids.last().map_or(1, |id| id + 1);
+        writer.blob = BlobWriter::new(next, self.dir.blob(next));
+        writer.next_blob = next + 1;
+        self.index.publish(index);
+
+        Ok(())
+    }
+
+    /// Hands the sealed segments to the compactor once the log has grown past the threshold
+    fn maybe_compact(&self) {
+        if self.compactor.is_running() {
+            return;
+        }
+
+        let merge = {
+            let mut writer = self.writer.lock().unwrap();
+            if !writer.wants_compaction() || !self.compactor.try_claim() {
+                return;
+            }
+            self.seal(&mut writer, false)
+        };
+
+        self.compactor.start(merge);
+    }
+
+    /// Seals the active segment and reserves the id after it for the merge output, along with
+    /// the blob files the merge rewrites, see `Writer::blob_victims`, and an id for their output
+    fn seal(&self, writer: &mut Writer, all: bool) -> Merge {
+        let sealed = writer.active;
+        writer.active += 2;
+
+        if all {
+            writer.seal_blob();
+        }
+        let blobs = writer.blob_victims(all);
+        let blob_output = writer.next_blob;
+        if !blobs.is_empty() {
+            writer.next_blob += 1;
+        }
+
+        Merge {
+            dir: Arc::clone(&self.dir),
+            index: Arc::clone(&self.index),
+            writer: Arc::clone(&self.writer),
+            compression: writer.options.compression,
+            sealed,
+            output: sealed + 1,
+            blobs,
+            blob_output,
+        }
     }
 
+    /// Compacts everything written so far and waits for it to finish
     pub fn compaction(&mut self) -> KvResult<()> {
-        let temp_dir = TempDir::new().expect("Unable to create temporary working directory");
-        let mut store = KvStore::open(temp_dir.path()).unwrap();
-
-        for key in self.table.keys() {
-            let _ = store.nocompactionset(
-                key.to_string(),
-                self.get(key.to_string())
-                    .expect("Unable to unwrap the Some(value) from the key")
-                    .expect("Unable to get the value from the Some(Value)")
-                    .to_string(),
-            );
+        if self.writer.lock().unwrap().options.read_only {
+            return Err(KvError::ReadOnly);
         }
 
-        let mut f = File::options()
-            .read(true)
-            .truncate(true)
-            .write(true)
-            .open(&self.path)
-            .unwrap();
+        let merge = loop {
+            let _ = self.compactor.wait();
 
-        let mut fr = File::options().read(true).open(&store.path).unwrap();
+            let mut writer = self.writer.lock().unwrap();
+            if self.compactor.try_claim() {
+                break self.seal(&mut writer, true);
+            }
+        };
 
-        self.table = store.table;
+        self.compactor.start(merge);
+        self.compactor.wait()
+    }
 
-        let mut buffer = String::new();
-        let _ = fr.read_to_string(&mut buffer);
-        let _ = f.write_all(buffer.as_bytes());
+    /// Flushes every write made so far to the disk, whatever the sync policy
+    pub fn sync(&self) -> KvResult<()> {
+        let mut writer = self.writer.lock().unwrap();
+        match &self.group {
+            // NOTE: A leader may be flushing what it took without the lock, so join its group
+            Some(group) => {
+                let version = writer.version;
+                drop(writer);
+                group.wait(&self.writer, version)
+            }
+            None => writer.flush(),
+        }
+    }
 
-        Ok(())
+    /// Bytes written to the log and blob files that haven't been flushed to the disk yet, which a
+    /// power failure could lose. With `SyncPolicy::Always` only writes that haven't returned yet
+    /// are unsynced
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.writer.lock().unwrap().unsynced.values().sum()
+    }
+
+    /// Hits, misses and evictions of the value cache, None if it is off, see
+    /// `KvStoreOptions::cache_size`
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Bytes in the log held by records that a later Set or Remove superseded
+    pub fn stale_bytes(&self) -> u64 {
+        self.writer.lock().unwrap().stale_bytes
+    }
+
+    /// Bytes taken up by every segment of the log
+    pub fn disk_size(&self) -> u64 {
+        self.writer.lock().unwrap().disk_size
+    }
+
+    /// Bytes taken up by every blob file, see `KvStoreOptions::blob_threshold`
+    pub fn blob_size(&self) -> u64 {
+        self.writer
+            .lock()
+            .unwrap()
+            .blobs
+            .values()
+            .map(|usage| usage.size)
+            .sum()
+    }
+
+    /// Blocks until a background compaction, if any, has finished
+    pub fn wait_for_compaction(&self) -> KvResult<()> {
+        self.compactor.wait()
     }
 
     pub fn list_key(&mut self) {
-        if self.table.is_empty() {
+        let now = now();
+        let index = self.index.load();
+        let mut keys = index
+            .iter()
+            .filter(|(_, pointer)| !pointer.is_expired(now))
+            .peekable();
+        if keys.peek().is_none() {
             println!("No key is found");
         }
         print!("Keys: ");
-        for i in self.table.clone().into_keys() {
-            print!("{}, ", i);
+        for (i, _) in keys {
+            print!("{}, ", String::from_utf8_lossy(i));
         }
     }
 
     pub fn count(&mut self) -> u32 {
-        self.table.clone().into_keys().count() as u32
+        let now = now();
+        self.index
+            .load()
+            .values()
+            .filter(|pointer| !pointer.is_expired(now))
+            .count() as u32
     }
 
     pub fn create_snapshot(&mut self) -> KvResult<PathBuf> {
-        let binding = PathBuf::from_str("/").unwrap();
-        let parent_dir = self.path.parent().unwrap_or(binding.as_ref());
+        /*
+         * A snapshot is a single segment holding only the live records, with the values that
+         * are in blob files written into it so it holds everything on its own
+         */
         let cur_date: chrono::DateTime<chrono::Local> = Local::now();
-        let mut f = File::options().read(true).open(&self.path).unwrap();
 
-        let new_log_path: PathBuf = parent_dir
+        let new_log_path: PathBuf = self
+            .dir
+            .path()
             .join("snapshots")
             .join(format!("log_{}.txt", cur_date.format("%Y-%m-%d_%H-%M-%S")));
 
-        let _ = create_dir(parent_dir.join("snapshots"));
-        let _ = File::create(&new_log_path);
-
-        let mut cur_f = File::options()
-            .write(true)
-            .truncate(true)
-            .open(&new_log_path)
-            .unwrap();
-
-        let mut buffer = Vec::new();
-
-        let _ = f.read_to_end(&mut buffer);
+        let _ = create_dir(self.dir.path().join("snapshots"));
 
-        let _ = cur_f.write_all(buffer.as_ref());
+        let now = now();
+        // NOTE: Written over from the start if a merge moves the records halfway through
+        self.read_index(|index| {
+            let mut cur_f = File::create(&new_log_path).map_err(|_| KvError::WriteError)?;
+            cur_f
+                .write_all(SEGMENT_MAGIC)
+                .map_err(|_| KvError::WriteError)?;
+            for pointer in index.values().filter(|pointer| !pointer.is_expired(now)) {
+                let mut record = self.files.read_record(&self.dir, *pointer)?;
+                if let (
+                    Command::SetBlob {
+                        key,
+                        blob,
+                        expires_at,
+                    },
+                    written_at,
+                ) = Command::decode_at(&record)?
+                {
+                    let val = self.files.read_blob(&self.dir, &key, blob)?;
+                    record = Command::Set {
+                        key,
+                        val,
+                        expires_at,
+                    }
+                    .encode_with(written_at, Compression::None);
+                }
+                cur_f
+                    .write_all(&self.dir.encrypt(record))
+                    .map_err(|_| KvError::WriteError)?;
+            }
+            Ok(())
+        })?;
 
         Ok(new_log_path)
     }
 
     pub fn load_snapshot(&mut self, path: PathBuf) -> KvResult<()> {
-        let mut f = File::options()
-            .truncate(true)
-            .write(true)
-            .open(&self.path)
-            .unwrap();
-
-        let mut fr = File::options().read(true).open(path).unwrap();
+        if self.writer.lock().unwrap().options.read_only {
+            return Err(KvError::ReadOnly);
+        }
+        self.compactor.wait()?;
 
-        let mut buffer: Vec<u8> = Vec::new();
+        {
+            let writer = self.writer.lock().unwrap();
+            let stale = self.dir.segment_ids()?;
+            let id = stale.last().copied().unwrap_or(0).max(writer.active) + 1;
 
-        let _ = fr.read_to_end(&mut buffer);
+            fs::copy(&path, self.dir.segment(id)).map_err(|_| KvError::OpenError { path })?;
 
-        let _ = f.write(&buffer);
+            for id in stale {
+                self.dir.remove_segment(id)?;
+            }
+            for id in self.dir.blob_ids()? {
+                self.dir.remove_blob(id)?;
+            }
+        }
 
-        Ok(())
+        self.build_index()
     }
 }
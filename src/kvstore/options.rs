@@ -0,0 +1,261 @@
+use super::{
+    crypto::{EncryptionKey, Keyring},
+    error::{KvError, KvResult},
+    KvStore,
+};
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+/// What the store puts after a segment's name for the files it keeps next to its segments, a
+/// segment can't be named any of them without being taken for one of those files
+const RESERVED_FILE_NAMES: [&str; 4] = ["compact", "hint", "blob", "upgrade"];
+
+/// When the store hands its sealed segments to the background compactor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionPolicy {
+    /// Once the segments take up more than this many bytes
+    Size(u64),
+    /// Once more than `ratio` of the bytes on disk are superseded, between 0 and 1, and the log
+    /// is bigger than `min_size` so tiny stores don't merge on every write
+    StaleRatio { ratio: f64, min_size: u64 },
+    /// Only when `KvStore::compaction` is called
+    Disabled,
+}
+
+/// When writes are flushed to the disk. A write that wasn't flushed yet can be lost on a power
+/// failure even though it returned, but the log is never left unreadable either way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave it to the OS
+    Never,
+    /// Before every write returns. Writers that wait at the same time share one flush
+    Always,
+    /// On a background thread this often, so at most that much of the latest writes is lost
+    Periodic(Duration),
+}
+
+/// Parses `none`, `always`, `periodic` for once a second or `periodic:<milliseconds>`
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SyncPolicy, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            "periodic" => Ok(SyncPolicy::Periodic(Duration::from_secs(1))),
+            policy => policy
+                .strip_prefix("periodic:")
+                .and_then(|millis| millis.parse().ok())
+                .map(|millis| SyncPolicy::Periodic(Duration::from_millis(millis)))
+                .ok_or_else(|| {
+                    format!(
+                        "expected none, always, periodic or periodic:<milliseconds>, got {}",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+/// How values are written to the log. Records say how they were written, so changing this
+/// doesn't break reading the ones already there and compaction rewrites them the new way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// As they are
+    #[default]
+    None,
+    /// With LZ4, for values of at least `min_size` bytes that it makes smaller
+    Lz4 { min_size: usize },
+}
+
+/// Which older versions of every key are kept around for `KvStore::get_history` and
+/// `KvStore::get_at`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HistoryPolicy {
+    /// Only the latest version
+    #[default]
+    Disabled,
+    /// The last this many versions, the latest one and removals included
+    Versions(usize),
+    /// Every version that was still the latest at some point within this long
+    Window(Duration),
+}
+
+impl HistoryPolicy {
+    pub fn is_enabled(&self) -> bool {
+        *self != HistoryPolicy::Disabled
+    }
+}
+
+/// How a store reads records from its segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadMode {
+    /// Seeks to every record in the segment file and reads it
+    #[default]
+    Buffered,
+    /// Copies records out of a memory-mapped view of the segment, which is mapped again once
+    /// the segment grew past it
+    Mmap,
+}
+
+/// Settings for opening a `KvStore`, built up like `std::fs::OpenOptions`
+///
+/// ```no_run
+/// use ferris_log::kvstore::options::{CompactionPolicy, KvStoreOptions};
+///
+/// let store = KvStoreOptions::new()
+///     .compaction(CompactionPolicy::Size(1024 * 1024 * 1024))
+///     .segment_size(64 * 1024 * 1024)
+///     .open("data")
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvStoreOptions {
+    pub(crate) compaction: CompactionPolicy,
+    pub(crate) segment_size: u64,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) data_file_name: String,
+    pub(crate) sweep_interval: Duration,
+    pub(crate) history: HistoryPolicy,
+    pub(crate) compression: Compression,
+    pub(crate) encryption: Option<EncryptionKey>,
+    pub(crate) old_keys: Vec<EncryptionKey>,
+    pub(crate) read_only: bool,
+    pub(crate) cache_size: usize,
+    pub(crate) read_mode: ReadMode,
+    pub(crate) blob_threshold: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction: CompactionPolicy::StaleRatio {
+                ratio: 0.5,
+                min_size: 1024,
+            },
+            segment_size: 1024 * 1024,
+            sync: SyncPolicy::Never,
+            data_file_name: "log".to_string(),
+            sweep_interval: Duration::from_secs(1),
+            history: HistoryPolicy::Disabled,
+            compression: Compression::None,
+            encryption: None,
+            old_keys: Vec::new(),
+            read_only: false,
+            cache_size: 0,
+            read_mode: ReadMode::Buffered,
+            blob_threshold: 0,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    pub fn compaction(mut self, policy: CompactionPolicy) -> KvStoreOptions {
+        self.compaction = policy;
+        self
+    }
+
+    /// Size at which the active segment is sealed and a new one is started
+    pub fn segment_size(mut self, size: u64) -> KvStoreOptions {
+        self.segment_size = size;
+        self
+    }
+
+    pub fn sync(mut self, policy: SyncPolicy) -> KvStoreOptions {
+        self.sync = policy;
+        self
+    }
+
+    /// Name the segments are written under, as `<id>.<name>`. Opening fails with
+    /// `InvalidFileName` if it is empty, holds a '.' or a path separator, or is one of the
+    /// suffixes the store uses for its other files
+    pub fn data_file_name(mut self, name: impl Into<String>) -> KvStoreOptions {
+        self.data_file_name = name.into();
+        self
+    }
+
+    /// How often expired keys are looked for and removed in the background
+    pub fn sweep_interval(mut self, interval: Duration) -> KvStoreOptions {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Keeps older versions of every key, records are stamped with the time they were written
+    /// while this is enabled
+    pub fn history(mut self, policy: HistoryPolicy) -> KvStoreOptions {
+        self.history = policy;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts everything written to the log, hints and snapshots with `key`. A store that was
+    /// encrypted fails to open with `WrongKey` unless it is given the key its records need
+    pub fn encryption(mut self, key: EncryptionKey) -> KvStoreOptions {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Lets records encrypted with an older key still be read. Compaction rewrites them with the
+    /// key given to `encryption`, after which the old key isn't needed anymore
+    pub fn decryption_key(mut self, key: EncryptionKey) -> KvStoreOptions {
+        self.old_keys.push(key);
+        self
+    }
+
+    /// Opens the store only to read it. Any number of read-only stores can have a directory open
+    /// at once, but not while a store that writes has it. Writes fail with `ReadOnly`, and
+    /// nothing on disk is changed, not even a torn write at the end of the log
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Keeps values that were read in memory, up to `bytes` of them, so reading them again
+    /// doesn't go to the disk. 0, the default, turns the cache off, see `KvStore::cache_stats`
+    pub fn cache_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.cache_size = bytes;
+        self
+    }
+
+    pub fn read_mode(mut self, mode: ReadMode) -> KvStoreOptions {
+        self.read_mode = mode;
+        self
+    }
+
+    /// Writes values of at least `bytes` to blob files of their own, leaving only where they are
+    /// in the log, so merging the log doesn't copy them over and over. 0, the default, keeps
+    /// every value in the log
+    pub fn blob_threshold(mut self, bytes: usize) -> KvStoreOptions {
+        self.blob_threshold = bytes;
+        self
+    }
+
+    pub(crate) fn keyring(&self) -> Option<Keyring> {
+        if self.encryption.is_none() && self.old_keys.is_empty() {
+            return None;
+        }
+        Some(Keyring::new(self.encryption.clone(), self.old_keys.clone()))
+    }
+
+    pub(crate) fn check_data_file_name(&self) -> KvResult<()> {
+        let name = &self.data_file_name;
+        if name.is_empty()
+            || name.contains(['.', '/', '\\'])
+            || RESERVED_FILE_NAMES.contains(&name.as_str())
+        {
+            return Err(KvError::InvalidFileName { name: name.clone() });
+        }
+        Ok(())
+    }
+
+    pub fn open(&self, path: impl Into<PathBuf>) -> KvResult<KvStore> {
+        KvStore::open_with(path.into(), self.clone())
+    }
+}
@@ -0,0 +1,673 @@
+use super::{
+    blob::{open_blob, read_blob, BlobRef},
+    command::{decrypt_record, encrypt_record, Command, RecordHeader, HEADER_LEN},
+    crypto::Keyring,
+    error::{KvError, KvResult},
+    options::ReadMode,
+};
+use arc_swap::ArcSwap;
+use memmap2::Mmap;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+// NOTE: Every binary segment starts with this, anything else is a legacy JSON log
+pub const SEGMENT_MAGIC: &[u8; 5] = b"FLOG\x01";
+
+// NOTE: How commands were written to log.txt before the binary format, only read to upgrade it
+#[derive(serde::Deserialize)]
+enum LegacyCommand {
+    Set { key: String, val: String },
+    Remove { key: String },
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set { key, val } => Command::set(key.into_bytes(), val.into_bytes()),
+            LegacyCommand::Remove { key } => Command::rm(key.into_bytes()),
+        }
+    }
+}
+
+/// Where a record lives on disk: the segment it was written to, its offset and its length. Also
+/// carries when the record expires, so expired keys can be told apart without reading them, and
+/// the version of the key the record holds and, while history is kept, when it was written. A
+/// record whose value went to a blob file carries where the value is as well
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogPointer {
+    pub segment: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub expires_at: Option<u64>,
+    /// Bumped on every write of the key while the store is open, 0 for records read back on open
+    pub version: u64,
+    /// In milliseconds since the unix epoch, None for records written while history wasn't kept
+    pub written_at: Option<u64>,
+    pub blob: Option<BlobRef>,
+}
+
+impl LogPointer {
+    pub fn new(segment: u64, offset: u64, len: u64) -> LogPointer {
+        LogPointer {
+            segment,
+            offset,
+            len,
+            expires_at: None,
+            version: 0,
+            written_at: None,
+            blob: None,
+        }
+    }
+
+    pub fn with_expiry(mut self, expires_at: Option<u64>) -> LogPointer {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn with_version(mut self, version: u64) -> LogPointer {
+        self.version = version;
+        self
+    }
+
+    pub fn with_written_at(mut self, written_at: Option<u64>) -> LogPointer {
+        self.written_at = written_at;
+        self
+    }
+
+    pub fn with_blob(mut self, blob: Option<BlobRef>) -> LogPointer {
+        self.blob = blob;
+        self
+    }
+
+    /// Whether the record had expired by `now`, in milliseconds since the unix epoch
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// The directory a store lives in, the name its segments are written under and the keys they
+/// are encrypted with
+#[derive(Debug)]
+pub struct DataDir {
+    path: PathBuf,
+    name: String,
+    keys: Option<Keyring>,
+    // NOTE: Every segment before this one was removed, merges only ever remove the oldest ones
+    oldest: AtomicU64,
+    // NOTE: Bumped every time a blob file is removed
+    blob_removals: AtomicU64,
+}
+
+impl DataDir {
+    pub fn new(path: PathBuf, name: String) -> DataDir {
+        DataDir {
+            path,
+            name,
+            keys: None,
+            oldest: AtomicU64::new(0),
+            blob_removals: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_keys(mut self, keys: Option<Keyring>) -> DataDir {
+        self.keys = keys;
+        self
+    }
+
+    pub fn keys(&self) -> Option<&Keyring> {
+        self.keys.as_ref()
+    }
+
+    /// Whether what is written now gets encrypted
+    pub fn encrypts(&self) -> bool {
+        self.keys().is_some_and(|keys| keys.encrypts())
+    }
+
+    /// Encrypts an encoded record the way it is written to disk, if the store is encrypted
+    pub fn encrypt(&self, record: Vec<u8>) -> Vec<u8> {
+        encrypt_record(record, self.keys())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Segments are written as `<id>.<name>`
+    pub fn segment(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.{}", id, self.name))
+    }
+
+    /// Where compaction writes a segment before it is complete
+    pub fn compact(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.{}.compact", id, self.name))
+    }
+
+    /// Where the hint of segment `id` is written, see `hint.rs`
+    pub fn hint(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.{}.hint", id, self.name))
+    }
+
+    /// Blob files are written as `<id>.<name>.blob`, see `blob.rs`
+    pub fn blob(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.{}.blob", id, self.name))
+    }
+
+    /// Where a merge writes a blob file before it is complete
+    pub fn compact_blob(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.{}.blob.compact", id, self.name))
+    }
+
+    /// Deletes segment `id` along with its hint
+    pub fn remove_segment(&self, id: u64) -> KvResult<()> {
+        fs::remove_file(self.segment(id)).map_err(|_| KvError::RemoveError)?;
+        self.oldest.fetch_max(id + 1, Ordering::SeqCst);
+        match fs::remove_file(self.hint(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(KvError::RemoveError),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn remove_blob(&self, id: u64) -> KvResult<()> {
+        fs::remove_file(self.blob(id)).map_err(|_| KvError::RemoveError)?;
+        self.blob_removals.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns the id of every blob file
+    pub fn blob_ids(&self) -> KvResult<Vec<u64>> {
+        let entries = fs::read_dir(&self.path).map_err(|_| KvError::OpenError {
+            path: self.path.clone(),
+        })?;
+
+        let suffix = format!(".{}.blob", self.name);
+        let mut ids: Vec<u64> = entries
+            .flatten()
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()?
+                    .strip_suffix(&suffix)?
+                    .parse()
+                    .ok()
+            })
+            .collect();
+
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Returns the id of every segment, oldest first
+    pub fn segment_ids(&self) -> KvResult<Vec<u64>> {
+        let entries = fs::read_dir(&self.path).map_err(|_| KvError::OpenError {
+            path: self.path.clone(),
+        })?;
+
+        let mut ids: Vec<u64> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension()? != self.name.as_str() {
+                    return None;
+                }
+                path.file_stem()?.to_str()?.parse().ok()
+            })
+            .collect();
+
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Deletes the output of any merge that was cut off before it completed
+    pub fn remove_unfinished_merges(&self) -> KvResult<()> {
+        let entries = fs::read_dir(&self.path).map_err(|_| KvError::OpenError {
+            path: self.path.clone(),
+        })?;
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "compact") {
+                fs::remove_file(&path).map_err(|_| KvError::RemoveError)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the record at `pointer`, decrypted if it was encrypted
+    pub fn read_record(&self, pointer: LogPointer) -> KvResult<Vec<u8>> {
+        let path = self.segment(pointer.segment);
+        let f = File::open(&path).map_err(|_| KvError::OpenError { path })?;
+        self.read_from(&f, pointer)
+    }
+
+    fn read_from(&self, f: &File, pointer: LogPointer) -> KvResult<Vec<u8>> {
+        let mut buffer = vec![0; pointer.len as usize];
+        read_exact_at(f, &mut buffer, pointer.offset).map_err(|_| KvError::ReadError)?;
+
+        decrypt_record(buffer, self.keys())
+    }
+
+    /// Reads the value of `key` that `blob` points at, decrypted if it was encrypted
+    pub fn read_blob(&self, key: &[u8], blob: BlobRef) -> KvResult<Vec<u8>> {
+        let (f, sealed) = open_blob(&self.blob(blob.file))?;
+        read_blob(&f, sealed, self.keys(), key, blob)
+    }
+
+    pub fn segment_len(&self, id: u64) -> u64 {
+        fs::metadata(self.segment(id)).map_or(0, |m| m.len())
+    }
+
+    pub fn blob_len(&self, id: u64) -> u64 {
+        fs::metadata(self.blob(id)).map_or(0, |m| m.len())
+    }
+}
+
+/// Reads exactly `buf.len()` bytes at `offset` without moving the cursor of `f`, so any number of
+/// reads can go through the same file at once
+pub fn read_exact_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(f, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+
+        let mut read = 0;
+        while read < buf.len() {
+            match f.seek_read(&mut buf[read..], offset + read as u64)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum OpenSegment {
+    File(File),
+    Mapped { file: File, map: ArcSwap<Mmap> },
+}
+
+type OpenBlob = (File, bool);
+
+/* NOTE:
+ *   The segments open for reading, so reads don't open the file every time. Clones share them, so
+ *   a handle made for one connection reads through the files the others already opened. The
+ *   table is only write locked to open or close files, files are read at an offset and mappings
+ *   are read as they are, so reads never wait on each other. A removed segment stays readable through a file or mapping
+ *   that was already open, which is closed the next time a read sees it was removed. Segment ids
+ *   are never reused, so an open segment is never replaced by another file of the same name. Blob
+ *   files are kept open the same way, and all closed once any of them is removed
+ */
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SegmentFiles {
+    mode: ReadMode,
+    files: Arc<RwLock<BTreeMap<u64, Arc<OpenSegment>>>>,
+    // NOTE: Every blob file open, with whether its values are sealed
+    blobs: Arc<RwLock<BTreeMap<u64, Arc<OpenBlob>>>>,
+    blob_removals: Arc<AtomicU64>,
+}
+
+impl SegmentFiles {
+    pub fn new(mode: ReadMode) -> SegmentFiles {
+        SegmentFiles {
+            mode,
+            ..SegmentFiles::default()
+        }
+    }
+
+    /// Same as `DataDir::read_blob`, through the blob files already open
+    pub fn read_blob(&self, dir: &DataDir, key: &[u8], blob: BlobRef) -> KvResult<Vec<u8>> {
+        let removals = dir.blob_removals.load(Ordering::SeqCst);
+        if self.blob_removals.swap(removals, Ordering::SeqCst) != removals {
+            self.blobs.write().unwrap().clear();
+        }
+
+        let open = self.blobs.read().unwrap().get(&blob.file).cloned();
+        let open = match open {
+            Some(open) => open,
+            None => {
+                let (f, sealed) = open_blob(&dir.blob(blob.file))?;
+                let mut blobs = self.blobs.write().unwrap();
+                Arc::clone(
+                    blobs
+                        .entry(blob.file)
+                        .or_insert_with(|| Arc::new((f, sealed))),
+                )
+            }
+        };
+
+        let (f, sealed) = &*open;
+        read_blob(f, *sealed, dir.keys(), key, blob)
+    }
+
+    /// Same as `DataDir::read_record`, through the files already open
+    pub fn read_record(&self, dir: &DataDir, pointer: LogPointer) -> KvResult<Vec<u8>> {
+        let oldest = dir.oldest.load(Ordering::SeqCst);
+        let (first, segment) = {
+            let files = self.files.read().unwrap();
+            (
+                files.keys().next().copied(),
+                files.get(&pointer.segment).cloned(),
+            )
+        };
+        if first.is_some_and(|id| id < oldest) {
+            let mut files = self.files.write().unwrap();
+            *files = files.split_off(&oldest);
+        }
+
+        let segment = match segment {
+            Some(segment) => segment,
+            None => {
+                let path = dir.segment(pointer.segment);
+                let file = File::open(&path).map_err(|_| KvError::OpenError { path })?;
+                let segment = match self.mode {
+                    ReadMode::Buffered => OpenSegment::File(file),
+                    ReadMode::Mmap => OpenSegment::Mapped {
+                        map: ArcSwap::from_pointee(map_segment(&file)?),
+                        file,
+                    },
+                };
+                let mut files = self.files.write().unwrap();
+                Arc::clone(
+                    files
+                        .entry(pointer.segment)
+                        .or_insert_with(|| Arc::new(segment)),
+                )
+            }
+        };
+
+        match &*segment {
+            OpenSegment::File(f) => dir.read_from(f, pointer),
+            OpenSegment::Mapped { file, map } => {
+                let end = pointer.offset + pointer.len;
+                let mut mapped = map.load_full();
+                // NOTE: Only the active segment grows, and only by appending
+                if end > mapped.len() as u64 {
+                    mapped = Arc::new(map_segment(file)?);
+                    map.store(Arc::clone(&mapped));
+                }
+                let record = mapped
+                    .get(pointer.offset as usize..end as usize)
+                    .ok_or(KvError::ReadError)?;
+                decrypt_record(record.to_vec(), dir.keys())
+            }
+        }
+    }
+}
+
+fn map_segment(file: &File) -> KvResult<Mmap> {
+    /* SAFETY:
+     *   The mapping is only read where records are, and the bytes of a record never change once
+     *   it is in the index. Segments are only appended to, a torn write is cut off on open before
+     *   anything reads, and a failed append is cut off again before any record points into it.
+     *   The directory lock keeps any other process that writes out while the store is open
+     */
+    unsafe { Mmap::map(file) }.map_err(|_| KvError::ReadError)
+}
+
+/// Opens a segment for appending, writing the segment header if it is new
+/// Flushes the directory holding `path`, so a file just created there is still found after a crash
+pub fn sync_dir(path: &Path) -> KvResult<()> {
+    // NOTE: Windows can't open a directory as a file, its entries are durable once the file is
+    #[cfg(unix)]
+    {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .map_err(|_| KvError::WriteError)?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// Opens the segment at `path` to append to it, starting it with the magic if it is new. Returns
+/// whether it was created
+pub fn open_for_append(path: &Path) -> KvResult<(File, bool)> {
+    let mut f = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|_| KvError::OpenError {
+            path: path.to_path_buf(),
+        })?;
+
+    let len = f.seek(SeekFrom::End(0)).map_err(|_| KvError::WriteError)?;
+    if len == 0 {
+        f.write_all(SEGMENT_MAGIC)
+            .map_err(|_| KvError::WriteError)?;
+        sync_dir(path)?;
+    }
+
+    Ok((f, len == 0))
+}
+
+/// Whether the file at `path` is a JSON log from before the binary format
+pub fn is_legacy(path: &Path) -> KvResult<bool> {
+    let f = File::open(path).map_err(|_| KvError::OpenError {
+        path: path.to_path_buf(),
+    })?;
+
+    let mut magic = Vec::with_capacity(SEGMENT_MAGIC.len());
+    f.take(SEGMENT_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .map_err(|_| KvError::ReadError)?;
+
+    Ok(!magic.is_empty() && magic != SEGMENT_MAGIC)
+}
+
+/// What `open` cut off the end of the log after an unclean shutdown
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TailRecovery {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub dropped: u64,
+}
+
+/// Rewrites a JSON log into the binary format in place. With `is_tail` set, a half written last
+/// line is dropped instead of failing the upgrade
+pub fn upgrade_legacy(path: &Path, is_tail: bool) -> KvResult<Option<TailRecovery>> {
+    let f = File::open(path).map_err(|_| KvError::OpenError {
+        path: path.to_path_buf(),
+    })?;
+    let tmp_path = path.with_extension("upgrade");
+    let (mut out, _) = open_for_append(&tmp_path)?;
+
+    let mut reader = BufReader::new(f);
+    let mut recovered = None;
+    let mut pos = 0;
+
+    loop {
+        let mut line = Vec::new();
+        let length = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|_| KvError::ReadError)? as u64;
+        if length == 0 {
+            break;
+        }
+
+        let complete = line.last() == Some(&b'\n');
+        match serde_json::from_slice::<LegacyCommand>(&line) {
+            Ok(cmd) => out
+                .write_all(&Command::from(cmd).encode())
+                .map_err(|_| KvError::WriteError)?,
+            Err(_) if line.iter().all(u8::is_ascii_whitespace) => (),
+            Err(_) if is_tail && !complete => {
+                recovered = Some(TailRecovery {
+                    path: path.to_path_buf(),
+                    offset: pos,
+                    dropped: length,
+                });
+            }
+            Err(_) => {
+                return Err(KvError::CorruptLog {
+                    path: path.to_path_buf(),
+                    offset: pos,
+                })
+            }
+        }
+
+        pos += length;
+    }
+
+    out.sync_all().map_err(|_| KvError::WriteError)?;
+    fs::rename(&tmp_path, path).map_err(|_| KvError::WriteError)?;
+
+    Ok(recovered)
+}
+
+/// Reads the records of one segment in order
+pub struct SegmentReader {
+    id: u64,
+    keys: Option<Keyring>,
+    path: PathBuf,
+    reader: BufReader<File>,
+    pos: u64,
+    len: u64,
+    torn: bool,
+}
+
+impl SegmentReader {
+    pub fn open(dir: &DataDir, id: u64) -> KvResult<SegmentReader> {
+        let path = dir.segment(id);
+        let f = File::open(&path).map_err(|_| KvError::OpenError { path: path.clone() })?;
+        let len = f.metadata().map_err(|_| KvError::ReadError)?.len();
+        let mut reader = BufReader::new(f);
+
+        let mut magic = [0; SEGMENT_MAGIC.len()];
+        let pos = match reader.read_exact(&mut magic) {
+            Ok(_) if &magic == SEGMENT_MAGIC => magic.len() as u64,
+            Ok(_) => return Err(KvError::CorruptLog { path, offset: 0 }),
+            // NOTE: A segment that was created but never written to, or whose header was torn
+            Err(_) => 0,
+        };
+
+        Ok(SegmentReader {
+            id,
+            keys: dir.keys.clone(),
+            path,
+            reader,
+            pos,
+            len,
+            torn: false,
+        })
+    }
+
+    /// Offset just past the last record read
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Whether the last failed read was a record running off the end of the file with no record
+    /// after it, which is what a crash in the middle of a write leaves behind
+    pub fn torn(&self) -> bool {
+        self.torn
+    }
+
+    pub fn next_record(&mut self) -> KvResult<Option<(Command, LogPointer)>> {
+        if self.pos >= self.len {
+            return Ok(None);
+        }
+
+        let corrupt = KvError::CorruptLog {
+            path: self.path.clone(),
+            offset: self.pos,
+        };
+        let remaining = self.len - self.pos;
+
+        if self.pos == 0 || remaining < HEADER_LEN as u64 {
+            self.torn = true;
+            return Err(corrupt);
+        }
+
+        let mut buf = vec![0; HEADER_LEN];
+        self.reader
+            .read_exact(&mut buf)
+            .map_err(|_| KvError::ReadError)?;
+
+        let header = RecordHeader::parse(&buf);
+        let record_len = (HEADER_LEN + header.body_len()) as u64;
+        if record_len > remaining {
+            // NOTE: Only torn if nothing after it is a record, a corrupt length would otherwise
+            // cut off every record behind it
+            self.torn = !self.record_follows()?;
+            return Err(corrupt);
+        }
+
+        buf.resize(record_len as usize, 0);
+        self.reader
+            .read_exact(&mut buf[HEADER_LEN..])
+            .map_err(|_| KvError::ReadError)?;
+
+        let decoded =
+            decrypt_record(buf, self.keys.as_ref()).and_then(|record| Command::decode_at(&record));
+        let (cmd, written_at) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e @ KvError::WrongKey { .. }) => return Err(e),
+            Err(_) => {
+                self.torn = record_len == remaining;
+                return Err(corrupt);
+            }
+        };
+        let pointer = LogPointer::new(self.id, self.pos, record_len)
+            .with_expiry(cmd.expires_at())
+            .with_written_at(written_at)
+            .with_blob(cmd.blob());
+        self.pos += record_len;
+
+        Ok(Some((cmd, pointer)))
+    }
+
+    /// Whether a whole record with a matching checksum starts anywhere in what is left of the file
+    fn record_follows(&mut self) -> KvResult<bool> {
+        let mut rest = Vec::new();
+        self.reader
+            .read_to_end(&mut rest)
+            .map_err(|_| KvError::ReadError)?;
+
+        Ok((0..rest.len()).any(|at| {
+            let record = &rest[at..];
+            if record.len() < HEADER_LEN {
+                return false;
+            }
+            let header = RecordHeader::parse(record);
+            let len = HEADER_LEN + header.body_len();
+            len <= record.len() && crc32fast::hash(&record[4..len]) == header.crc
+        }))
+    }
+
+    /// Cuts the segment back to `at`, which is at most the end of the last good record
+    pub fn truncate(self, at: u64) -> KvResult<TailRecovery> {
+        let f = File::options()
+            .write(true)
+            .open(&self.path)
+            .map_err(|_| KvError::OpenError {
+                path: self.path.clone(),
+            })?;
+        f.set_len(at).map_err(|_| KvError::WriteError)?;
+        f.sync_all().map_err(|_| KvError::WriteError)?;
+
+        Ok(self.tail(at))
+    }
+
+    /// What cutting the segment back to `at` drops, without cutting it
+    pub fn tail(&self, at: u64) -> TailRecovery {
+        TailRecovery {
+            dropped: self.len - at,
+            offset: at,
+            path: self.path.clone(),
+        }
+    }
+}
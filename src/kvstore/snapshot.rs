@@ -0,0 +1,118 @@
+use super::{
+    error::{KvError, KvResult},
+    expiry::now,
+    is_backwards, KvStore, Scan,
+};
+use std::ops::{Bound, RangeBounds};
+
+/// A read-only view of the store pinned to the last write made before it was taken. Gets and
+/// scans through it keep seeing the store as it was then while writes go on, and the versions it
+/// needs are kept, through compactions too, until it is dropped
+///
+/// ```no_run
+/// use ferris_log::kvstore::KvStore;
+///
+/// let mut store = KvStore::open("data").unwrap();
+/// let snapshot = store.snapshot();
+/// store.set("key".to_owned(), "new".to_owned()).unwrap();
+/// // Still the value from before the set
+/// let old = snapshot.get("key".to_owned()).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Snapshot {
+    store: KvStore,
+    seq: u64,
+    // NOTE: Keys that expire after the snapshot was taken are still there in it
+    now: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(store: KvStore) -> Snapshot {
+        let seq = {
+            let _writer = store.writer.lock().unwrap();
+            let mut index = store.index.edit();
+            let seq = index.open_snapshot();
+            store.index.publish(index);
+            seq
+        };
+        Snapshot {
+            store,
+            seq,
+            now: now(),
+        }
+    }
+
+    /// The version of the last write the snapshot sees
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Fails with `ParseError` if the value isn't valid UTF-8, use `get_bytes` for those
+    pub fn get(&self, key: String) -> KvResult<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(val) => Ok(Some(
+                String::from_utf8(val).map_err(|_| KvError::ParseError)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> KvResult<Option<Vec<u8>>> {
+        self.store.read_index(|index| {
+            match index
+                .get_as_of(key, self.seq)
+                .filter(|pointer| !pointer.is_expired(self.now))
+            {
+                Some(pointer) => self.store.read_value(pointer),
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Every key in `range` with its value as of the snapshot, see `KvStore::scan`
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>> + Clone) -> KvResult<Scan> {
+        if is_backwards(&range) {
+            return Ok(Vec::new().into_iter());
+        }
+        self.read_pairs(range, &[])
+    }
+
+    /// Every key starting with `prefix` with its value as of the snapshot
+    pub fn scan_prefix(&self, prefix: &[u8]) -> KvResult<Scan> {
+        self.read_pairs((Bound::Included(prefix.to_vec()), Bound::Unbounded), prefix)
+    }
+
+    fn read_pairs(
+        &self,
+        range: impl RangeBounds<Vec<u8>> + Clone,
+        prefix: &[u8],
+    ) -> KvResult<Scan> {
+        self.store.read_index(|index| {
+            let mut pairs = Vec::new();
+            for key in index
+                .keys_at(range.clone())
+                .into_iter()
+                .take_while(|key| key.starts_with(prefix))
+            {
+                let pointer = match index.get_as_of(&key, self.seq) {
+                    Some(pointer) if !pointer.is_expired(self.now) => pointer,
+                    _ => continue,
+                };
+                if let Some(val) = self.store.read_value(pointer)? {
+                    pairs.push((key, val));
+                }
+            }
+
+            Ok(pairs.into_iter())
+        })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _writer = self.store.writer.lock().unwrap();
+        let mut index = self.store.index.edit();
+        index.close_snapshot(self.seq);
+        self.store.index.publish(index);
+    }
+}
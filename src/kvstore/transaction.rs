@@ -0,0 +1,99 @@
+use super::{
+    batch::WriteBatch,
+    error::{KvError, KvResult},
+    KvStore,
+};
+use std::collections::BTreeMap;
+
+/// Reads keys and buffers writes, then commits the writes all at once only if none of the keys it
+/// read were changed in the meantime. Otherwise the commit fails with `TransactionConflict` and
+/// nothing is written, so the transaction can be run again
+///
+/// ```no_run
+/// use ferris_log::kvstore::KvStore;
+///
+/// let store = KvStore::open("data").unwrap();
+/// let mut txn = store.transaction();
+/// let balance: u64 = txn.get("balance".to_owned()).unwrap().unwrap().parse().unwrap();
+/// txn.set("balance".to_owned(), (balance - 10).to_string());
+/// txn.commit().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Transaction {
+    store: KvStore,
+    // NOTE: The version each key was read at, None if it wasn't there
+    reads: BTreeMap<Vec<u8>, Option<u64>>,
+    // NOTE: The last write of each key, None for a remove
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(store: KvStore) -> Transaction {
+        Transaction {
+            store,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Fails with `ParseError` if the value isn't valid UTF-8, use `get_bytes` for those
+    pub fn get(&mut self, key: String) -> KvResult<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(val) => Ok(Some(
+                String::from_utf8(val).map_err(|_| KvError::ParseError)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Sees the writes buffered so far, the key has to be unchanged for the commit to go through
+    pub fn get_bytes(&mut self, key: &[u8]) -> KvResult<Option<Vec<u8>>> {
+        if let Some(write) = self.writes.get(key) {
+            return Ok(write.clone());
+        }
+
+        let read = self.store.get_versioned(key)?;
+        // NOTE: A key read twice has to be unchanged since the first read
+        self.reads
+            .entry(key.to_vec())
+            .or_insert_with(|| read.as_ref().map(|(_, version)| *version));
+
+        Ok(read.map(|(val, _)| val))
+    }
+
+    pub fn set(&mut self, key: String, val: String) {
+        self.set_bytes(key.into_bytes(), val.into_bytes())
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        self.writes.insert(key, Some(val));
+    }
+
+    /// Fails with `RemoveError` if the key isn't there, which counts as reading it
+    pub fn remove(&mut self, key: String) -> KvResult<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> KvResult<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(KvError::RemoveError);
+        }
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Writes everything that was set or removed, or fails with `TransactionConflict` if a key
+    /// that was read has been written since
+    pub fn commit(self) -> KvResult<()> {
+        let mut batch = WriteBatch::new();
+        for (key, write) in self.writes {
+            match write {
+                Some(val) => batch.set(key, val),
+                None => batch.remove(key),
+            }
+        }
+
+        let mut store = self.store;
+        store.commit(&self.reads, batch)
+    }
+}
@@ -1,4 +1,3 @@
-extern crate bincode;
 extern crate chrono;
 extern crate clap;
 extern crate lazy_static;
@@ -6,6 +6,8 @@ pub enum ServerError {
     UnableToDecodeBytes { e: Box<dyn Error> },
     CommandNotFound,
     GetFoundNone,
+    MalformedRequest,
+    RequestTooLarge { len: usize },
 }
 
 impl Display for ServerError {
@@ -17,6 +19,10 @@ impl Display for ServerError {
             Self::UnableToDecodeBytes { e } => writeln!(f, "UnableToDecodeBytes, Error: {}", e),
             Self::CommandNotFound => writeln!(f, "Command is not found"),
             Self::GetFoundNone => writeln!(f, "Found None"),
+            Self::MalformedRequest => writeln!(f, "Request is malformed"),
+            Self::RequestTooLarge { len } => {
+                writeln!(f, "Request of {} bytes is over the limit", len)
+            }
         }
     }
 }
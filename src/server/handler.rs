@@ -4,28 +4,29 @@ use std::{
     net::TcpStream,
 };
 
-use bincode::{config, decode_from_slice, encode_to_vec};
 use slog::{info, warn, Logger};
 
-use crate::kv_engine::KvEngine;
+use crate::{kv_engine::KvEngine, kvstore::error::KvError};
 
-use super::error::ServerError;
-
-struct Header {
-    command: u8,
-    keysize: u8,
-    valuesize: u8,
-}
+use super::{
+    error::ServerError,
+    protocol::{
+        decode_batch, read_integer, split_cas_value, split_ttl_value, write_cas, write_incr,
+        write_pairs, write_status, write_ttl, write_value, Header, BATCH, CAS, GET, HEADER_LEN,
+        INCR, MAX_FRAME_LEN, REMOVE, REMOVE_IF_EQUALS, SCAN, SCAN_PREFIX, SET, SET_IF_ABSENT,
+        SET_TTL, TTL,
+    },
+};
 
 #[derive(Debug)]
 struct CliCommand {
     command: u8,
-    key: String,
-    value: Option<String>,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
 }
 
 impl CliCommand {
-    fn new(command: u8, key: String, value: Option<String>) -> CliCommand {
+    fn new(command: u8, key: Vec<u8>, value: Option<Vec<u8>>) -> CliCommand {
         CliCommand {
             command,
             key,
@@ -34,21 +35,11 @@ impl CliCommand {
     }
 }
 
-impl Header {
-    fn new(command: u8, keysize: u8, valuesize: u8) -> Header {
-        Header {
-            command,
-            keysize,
-            valuesize,
-        }
-    }
-}
-
 fn handle_listener(stream: &mut TcpStream) -> Result<CliCommand, ServerError> {
     /*
      * Reads data from the TcpStream and parse them into the CliCommand struct
      */
-    let mut buf: [u8; 3] = [0, 0, 0];
+    let mut buf = [0; HEADER_LEN];
 
     let _ = stream.flush();
 
@@ -57,29 +48,34 @@ fn handle_listener(stream: &mut TcpStream) -> Result<CliCommand, ServerError> {
         Err(e) => return Err(ServerError::FailedToReadStream { e: Box::new(e) }),
     }
 
-    let header = Header::new(buf[0], buf[1], buf[2]);
-    let mut buf: Vec<u8> = Vec::new();
+    let header = Header::parse(&buf);
 
-    match stream.read_to_end(&mut buf) {
+    // NOTE: The sizes come straight off the wire, refuse them before allocating anything
+    if header.body_len() > MAX_FRAME_LEN {
+        return Err(ServerError::RequestTooLarge {
+            len: header.body_len(),
+        });
+    }
+
+    let mut key = vec![0; header.keysize as usize];
+    let mut val = vec![0; header.valuesize as usize];
+    match stream
+        .read_exact(&mut key)
+        .and_then(|_| stream.read_exact(&mut val))
+    {
         Ok(_) => (),
         Err(e) => return Err(ServerError::FailedToReadStream { e: Box::new(e) }),
     }
 
-    let keybyte = &buf[..{ header.keysize as usize }];
-
-    let valuebyte =
-        &buf[{ header.keysize as usize }..{ header.keysize as usize + header.valuesize as usize }];
-
-    let key: String = match decode_from_slice(keybyte, config::standard()) {
-        Ok(k) => k.0,
-        Err(e) => return Err(ServerError::UnableToDecodeBytes { e: Box::new(e) }),
-    };
-
-    let value = decode_from_slice(valuebyte, config::standard());
-
-    let val = match value {
-        Ok(val) => Some(val.0),
-        Err(_) => None,
+    // NOTE: Only sets, scans, batches and conditional writes carry a value, an empty one is still
+    // a value
+    let val = if matches!(
+        header.command,
+        SET | SET_TTL | SCAN | BATCH | CAS | SET_IF_ABSENT | REMOVE_IF_EQUALS | INCR
+    ) {
+        Some(val)
+    } else {
+        None
     };
 
     let command = CliCommand::new(header.command, key, val);
@@ -87,6 +83,16 @@ fn handle_listener(stream: &mut TcpStream) -> Result<CliCommand, ServerError> {
     Ok(command)
 }
 
+// NOTE: Only answered once the engine returned, so a client waiting for the answer knows the
+// write is as durable as the server's sync policy makes it
+fn answer_write(
+    stream: &mut TcpStream,
+    res: Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    write_status(stream, res.is_ok())?;
+    res
+}
+
 fn execute_command<T: KvEngine>(
     logger: Logger,
     stream: &mut TcpStream,
@@ -101,27 +107,23 @@ fn execute_command<T: KvEngine>(
     let key = parsed.key;
     let val = parsed.value;
     match command {
-        0 => {
-            store.tset(key, val.unwrap())?;
+        SET => {
+            answer_write(stream, store.tset(key, val.unwrap()))?;
 
             info!(logger, "Application Info"; "Info" => "Set command succesfully ran");
         }
-        1 => {
+        GET => {
             let res = store.tget(key)?;
 
             match res {
                 Some(l) => {
-                    let byte = encode_to_vec(l, config::standard())
-                        .unwrap_or("Get Error Found None".as_bytes().to_vec());
-                    let _ = stream.write(&[byte.len() as u8]).unwrap();
-                    let _ = stream.write(&byte[..]).unwrap();
+                    write_value(stream, &l)?;
 
                     info!(logger, "Application Info"; "Info" => "Get command succesfully ran");
-                    info!(logger, "Application Info"; "Info" => format!("Sent back {:?}",&byte));
+                    info!(logger, "Application Info"; "Info" => format!("Sent back {} bytes", l.len()));
                 }
                 None => {
-                    let byte = encode_to_vec("Key not found", config::standard()).unwrap();
-                    let _ = stream.write(&byte[..]);
+                    // NOTE: Sending nothing back is how the client knows the key wasn't found
                     warn!(logger,
                         "Application Warning";
                         "Error:" => format!("{:?}",ServerError::GetFoundNone)
@@ -130,10 +132,92 @@ fn execute_command<T: KvEngine>(
                 }
             }
         }
-        2 => {
-            store.tremove(key)?;
+        SET_TTL => {
+            let val = val.unwrap_or_default();
+            let (ttl, val) = split_ttl_value(&val).ok_or(ServerError::MalformedRequest)?;
+            answer_write(stream, store.tset_with_ttl(key, val.to_vec(), ttl))?;
+
+            info!(logger, "Application Info"; "Info" => format!("Set command with a TTL of {:?} succesfully ran", ttl));
+        }
+        TTL => match store.tttl(key)? {
+            Some(ttl) => {
+                write_ttl(stream, ttl)?;
+                info!(logger, "Application Info"; "Info" => "TTL command succesfully ran");
+            }
+            None => {
+                // NOTE: Like a get, sending nothing back means the key wasn't found
+                warn!(logger,
+                    "Application Warning";
+                    "Error:" => format!("{:?}",ServerError::GetFoundNone)
+                );
+                return Err(Box::new(ServerError::GetFoundNone));
+            }
+        },
+        REMOVE => {
+            answer_write(stream, store.tremove(key))?;
             info!(logger, "Application Info"; "Info" => "Remove command succesfully ran");
         }
+        SCAN => {
+            let end = val.unwrap_or_default();
+            let pairs = if end.is_empty() {
+                store.tscan(key..)?
+            } else {
+                store.tscan(key..end)?
+            };
+            write_pairs(stream, &pairs)?;
+
+            info!(logger, "Application Info"; "Info" => format!("Scan command sent back {} keys", pairs.len()));
+        }
+        SCAN_PREFIX => {
+            let pairs = store.tscan_prefix(key)?;
+            write_pairs(stream, &pairs)?;
+
+            info!(logger, "Application Info"; "Info" => format!("Scan prefix command sent back {} keys", pairs.len()));
+        }
+        BATCH => {
+            let val = val.unwrap_or_default();
+            let batch = decode_batch(&val).ok_or(ServerError::MalformedRequest)?;
+            let len = batch.len();
+            answer_write(stream, store.tbatch(batch))?;
+
+            info!(logger, "Application Info"; "Info" => format!("Batch of {} writes succesfully ran", len));
+        }
+        CAS | SET_IF_ABSENT | REMOVE_IF_EQUALS => {
+            let val = val.unwrap_or_default();
+            let res = match command {
+                CAS => {
+                    let (expected, new) =
+                        split_cas_value(&val).ok_or(ServerError::MalformedRequest)?;
+                    store.tcompare_and_swap(key, expected, new)?
+                }
+                SET_IF_ABSENT => store.tset_if_absent(key, val)?,
+                _ => store.tremove_if_equals(key, val)?,
+            };
+            write_cas(stream, &res)?;
+
+            info!(logger, "Application Info"; "Info" => format!("Conditional write {}", if res.is_ok() { "succesfully ran" } else { "found a different value" }));
+        }
+        INCR => {
+            let val = val.unwrap_or_default();
+            let delta =
+                read_integer(&mut val.as_slice()).map_err(|_| ServerError::MalformedRequest)?;
+            let new = match store.tincr(key, delta) {
+                Ok(new) => new,
+                Err(e) => {
+                    // NOTE: Only a value that can't be counted is sent back, anything else
+                    // closes the connection without an answer
+                    if let Some(e @ (KvError::NotAnInteger | KvError::Overflow)) =
+                        e.downcast_ref::<KvError>()
+                    {
+                        write_incr(stream, Err(e))?;
+                    }
+                    return Err(e);
+                }
+            };
+            write_incr(stream, Ok(new))?;
+
+            info!(logger, "Application Info"; "Info" => format!("Incr command succesfully ran, the value is now {}", new));
+        }
         _ => {
             return Err(Box::new(ServerError::CommandNotFound));
         }
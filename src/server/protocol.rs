@@ -0,0 +1,383 @@
+use crate::kvstore::{
+    batch::WriteBatch,
+    command::Command,
+    error::{CasResult, KvError},
+    expiry::{now, Ttl},
+    Pair,
+};
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+/* NOTE:
+ *   Every request is laid out as
+ *   | command: u8 | key_len: u32 | val_len: u32 | key | val |
+ *   and a get is answered with
+ *   | val_len: u32 | val |
+ *   or nothing at all if the key isn't found. A scan sends the start of the range as the key and
+ *   the end as the value, an empty end meaning no end, and a prefix scan sends the prefix as the
+ *   key. Both are answered with
+ *   | count: u32 | key_len: u32 | val_len: u32 | key | val | ...
+ *   A set with a TTL puts the TTL in milliseconds in front of the value
+ *   | ttl_ms: u64 | val |
+ *   and asking for a key's TTL is answered with
+ *   | expires: u8 | remaining_ms: u64 |
+ *   or nothing at all if the key isn't found. A batch has no key, its value is the sets, sets with
+ *   a TTL and removes it holds, each laid out as a request of its own. Sets, sets with a TTL,
+ *   removes and batches are answered once the write is done, which under `SyncPolicy::Always` is
+ *   once it is on the disk, with
+ *   | written: u8 |
+ *   0 meaning the write failed. All integers are little endian. A compare and swap sends the value it expects and the one it writes as
+ *   | has_expected: u8 | expected_len: u32 | expected | has_new: u8 | new_len: u32 | new |
+ *   while setting if absent sends just the value and removing if equal the expected one. All
+ *   three are answered with
+ *   | swapped: u8 |
+ *   followed, when it wasn't swapped, by what the key holds instead
+ *   | found: u8 | val_len: u32 | val |
+ *   An increment sends how much to add as its value
+ *   | delta: i64 |
+ *   and is answered with
+ *   | status: u8 | val: i64 |
+ *   the status being INCR_OK along with the new value, or why the key couldn't be counted with a
+ *   val of 0. Nothing at all is sent back if it failed for any other reason
+ */
+pub const HEADER_LEN: usize = 9;
+
+/// The most a request's key and value may add up to, and the most any single length in an answer
+/// may be. Anything longer is refused before a buffer is allocated for it
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+pub const SET: u8 = 0;
+pub const GET: u8 = 1;
+pub const REMOVE: u8 = 2;
+pub const SCAN: u8 = 3;
+pub const SCAN_PREFIX: u8 = 4;
+pub const SET_TTL: u8 = 5;
+pub const TTL: u8 = 6;
+pub const BATCH: u8 = 7;
+pub const CAS: u8 = 8;
+pub const SET_IF_ABSENT: u8 = 9;
+pub const REMOVE_IF_EQUALS: u8 = 10;
+pub const INCR: u8 = 11;
+
+pub const INCR_OK: u8 = 0;
+pub const INCR_NOT_AN_INTEGER: u8 = 1;
+pub const INCR_OVERFLOW: u8 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub command: u8,
+    pub keysize: u32,
+    pub valuesize: u32,
+}
+
+impl Header {
+    pub fn new(command: u8, keysize: u32, valuesize: u32) -> Header {
+        Header {
+            command,
+            keysize,
+            valuesize,
+        }
+    }
+
+    pub fn parse(buf: &[u8; HEADER_LEN]) -> Header {
+        Header {
+            command: buf[0],
+            keysize: u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]),
+            valuesize: u32::from_le_bytes([buf[5], buf[6], buf[7], buf[8]]),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0; HEADER_LEN];
+        buf[0] = self.command;
+        buf[1..5].copy_from_slice(&self.keysize.to_le_bytes());
+        buf[5..].copy_from_slice(&self.valuesize.to_le_bytes());
+        buf
+    }
+
+    /// Bytes of key and value that follow the header
+    pub fn body_len(&self) -> usize {
+        self.keysize as usize + self.valuesize as usize
+    }
+}
+
+/// Reads `len` bytes, failing without allocating anything if `len` is over `MAX_FRAME_LEN`
+pub fn read_frame(stream: &mut impl Read, len: u32) -> io::Result<Vec<u8>> {
+    if len as usize > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is over the limit", len),
+        ));
+    }
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn write_request(
+    stream: &mut impl Write,
+    command: u8,
+    key: &[u8],
+    val: &[u8],
+) -> io::Result<()> {
+    let header = Header::new(command, key.len() as u32, val.len() as u32);
+    stream.write_all(&header.encode())?;
+    stream.write_all(key)?;
+    stream.write_all(val)?;
+    stream.flush()
+}
+
+/// Answers a SET, SET_TTL, REMOVE or BATCH request
+pub fn write_status(stream: &mut impl Write, written: bool) -> io::Result<()> {
+    stream.write_all(&[written as u8])?;
+    stream.flush()
+}
+
+/// Reads the answer to a SET, SET_TTL, REMOVE or BATCH request, whether the write went through
+pub fn read_status(stream: &mut impl Read) -> io::Result<bool> {
+    let mut written = [0; 1];
+    stream.read_exact(&mut written)?;
+    Ok(written[0] != 0)
+}
+
+pub fn write_value(stream: &mut impl Write, val: &[u8]) -> io::Result<()> {
+    stream.write_all(&(val.len() as u32).to_le_bytes())?;
+    stream.write_all(val)?;
+    stream.flush()
+}
+
+/// Reads the answer to a get, fails if the server sent nothing back because the key wasn't found
+pub fn read_value(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut size = [0; 4];
+    stream.read_exact(&mut size)?;
+
+    read_frame(stream, u32::from_le_bytes(size))
+}
+
+pub fn write_pairs(stream: &mut impl Write, pairs: &[Pair]) -> io::Result<()> {
+    stream.write_all(&(pairs.len() as u32).to_le_bytes())?;
+    for (key, val) in pairs {
+        stream.write_all(&(key.len() as u32).to_le_bytes())?;
+        stream.write_all(&(val.len() as u32).to_le_bytes())?;
+        stream.write_all(key)?;
+        stream.write_all(val)?;
+    }
+    stream.flush()
+}
+
+pub fn read_pairs(stream: &mut impl Read) -> io::Result<Vec<Pair>> {
+    let mut count = [0; 4];
+    stream.read_exact(&mut count)?;
+
+    let mut pairs = Vec::new();
+    for _ in 0..u32::from_le_bytes(count) {
+        let mut sizes = [0; 8];
+        stream.read_exact(&mut sizes)?;
+
+        let key = read_frame(
+            stream,
+            u32::from_le_bytes([sizes[0], sizes[1], sizes[2], sizes[3]]),
+        )?;
+        let val = read_frame(
+            stream,
+            u32::from_le_bytes([sizes[4], sizes[5], sizes[6], sizes[7]]),
+        )?;
+        pairs.push((key, val));
+    }
+
+    Ok(pairs)
+}
+
+/// The value of a SET_TTL request
+pub fn ttl_value(ttl: Duration, val: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + val.len());
+    buf.extend_from_slice(&(ttl.as_millis() as u64).to_le_bytes());
+    buf.extend_from_slice(val);
+    buf
+}
+
+/// Splits the value of a SET_TTL request back into the TTL and the value
+pub fn split_ttl_value(buf: &[u8]) -> Option<(Duration, &[u8])> {
+    if buf.len() < 8 {
+        return None;
+    }
+    let (ttl, val) = buf.split_at(8);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(ttl);
+    Some((Duration::from_millis(u64::from_le_bytes(bytes)), val))
+}
+
+pub fn write_ttl(stream: &mut impl Write, ttl: Ttl) -> io::Result<()> {
+    let (expires, remaining) = match ttl {
+        Ttl::Persistent => (0_u8, 0),
+        Ttl::Expires(remaining) => (1, remaining.as_millis() as u64),
+    };
+    stream.write_all(&[expires])?;
+    stream.write_all(&remaining.to_le_bytes())?;
+    stream.flush()
+}
+
+/// Reads the answer to a TTL request, fails if the server sent nothing back because the key
+/// wasn't found
+pub fn read_ttl(stream: &mut impl Read) -> io::Result<Ttl> {
+    let mut buf = [0; 9];
+    stream.read_exact(&mut buf)?;
+
+    let mut remaining = [0; 8];
+    remaining.copy_from_slice(&buf[1..]);
+    Ok(match buf[0] {
+        0 => Ttl::Persistent,
+        _ => Ttl::Expires(Duration::from_millis(u64::from_le_bytes(remaining))),
+    })
+}
+
+/// The value of a BATCH request
+pub fn encode_batch(batch: &WriteBatch) -> Vec<u8> {
+    let now = now();
+    let mut buf = Vec::new();
+    for cmd in batch.ops() {
+        let (command, key, val) = match cmd {
+            Command::Set {
+                key,
+                val,
+                expires_at: None,
+            } => (SET, key, val.clone()),
+            Command::Set {
+                key,
+                val,
+                expires_at: Some(at),
+            } => {
+                let ttl = Duration::from_millis(at.saturating_sub(now));
+                (SET_TTL, key, ttl_value(ttl, val))
+            }
+            Command::Remove { key } => (REMOVE, key, Vec::new()),
+            _ => continue,
+        };
+        // NOTE: Writing into a Vec can't fail
+        let _ = write_request(&mut buf, command, key, &val);
+    }
+    buf
+}
+
+/// Reads the value of a BATCH request back into a batch, None if it doesn't hold whole requests
+/// or holds anything but sets and removes
+pub fn decode_batch(mut buf: &[u8]) -> Option<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    while !buf.is_empty() {
+        let mut header = [0; HEADER_LEN];
+        buf.read_exact(&mut header).ok()?;
+        let header = Header::parse(&header);
+
+        // NOTE: The lengths come off the wire, a batch can't hold more than what is left of it
+        if header.body_len() > buf.len() {
+            return None;
+        }
+        let key = read_frame(&mut buf, header.keysize).ok()?;
+        let val = read_frame(&mut buf, header.valuesize).ok()?;
+
+        match header.command {
+            SET => batch.set(key, val),
+            SET_TTL => {
+                let (ttl, val) = split_ttl_value(&val)?;
+                batch.set_with_ttl(key, val.to_vec(), ttl);
+            }
+            REMOVE => batch.remove(key),
+            _ => return None,
+        }
+    }
+    Some(batch)
+}
+
+fn write_option(buf: &mut Vec<u8>, val: Option<&[u8]>) {
+    buf.push(val.is_some() as u8);
+    let val = val.unwrap_or_default();
+    buf.extend_from_slice(&(val.len() as u32).to_le_bytes());
+    buf.extend_from_slice(val);
+}
+
+fn read_option(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 5];
+    stream.read_exact(&mut header)?;
+
+    let val = read_frame(
+        stream,
+        u32::from_le_bytes([header[1], header[2], header[3], header[4]]),
+    )?;
+    Ok(Some(val).filter(|_| header[0] != 0))
+}
+
+/// The value of a CAS request
+pub fn cas_value(expected: Option<&[u8]>, new: Option<&[u8]>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_option(&mut buf, expected);
+    write_option(&mut buf, new);
+    buf
+}
+
+/// The value a compare and swap expects and the one it writes
+pub type CasValues = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Splits the value of a CAS request back into the expected and the new value
+pub fn split_cas_value(mut buf: &[u8]) -> Option<CasValues> {
+    let expected = read_option(&mut buf).ok()?;
+    let new = read_option(&mut buf).ok()?;
+    Some((expected, new)).filter(|_| buf.is_empty())
+}
+
+pub fn write_cas(stream: &mut impl Write, res: &CasResult) -> io::Result<()> {
+    let mut buf = Vec::new();
+    match res {
+        Ok(()) => buf.push(1),
+        Err(e) => {
+            buf.push(0);
+            write_option(&mut buf, e.current.as_deref());
+        }
+    }
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+/// Reads the answer to a CAS, SET_IF_ABSENT or REMOVE_IF_EQUALS request, handing back what the
+/// key holds when it wasn't swapped
+pub fn read_cas(stream: &mut impl Read) -> io::Result<Result<(), Option<Vec<u8>>>> {
+    let mut swapped = [0; 1];
+    stream.read_exact(&mut swapped)?;
+    match swapped[0] {
+        0 => Ok(Err(read_option(stream)?)),
+        _ => Ok(Ok(())),
+    }
+}
+
+/// Reads the value of an INCR request
+pub fn read_integer(stream: &mut impl Read) -> io::Result<i64> {
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(i64::from_le_bytes(buf))
+}
+
+/// Answers an INCR request, only `NotAnInteger` and `Overflow` can be sent back as errors
+pub fn write_incr(stream: &mut impl Write, res: Result<i64, &KvError>) -> io::Result<()> {
+    let (status, val) = match res {
+        Ok(val) => (INCR_OK, val),
+        Err(KvError::Overflow) => (INCR_OVERFLOW, 0),
+        Err(_) => (INCR_NOT_AN_INTEGER, 0),
+    };
+    stream.write_all(&[status])?;
+    stream.write_all(&val.to_le_bytes())?;
+    stream.flush()
+}
+
+/// Reads the answer to an INCR request, handing back why the key couldn't be counted. Fails if
+/// the server sent nothing back
+pub fn read_incr(stream: &mut impl Read) -> io::Result<Result<i64, KvError>> {
+    let mut status = [0; 1];
+    stream.read_exact(&mut status)?;
+    let val = read_integer(stream)?;
+    Ok(match status[0] {
+        INCR_OK => Ok(val),
+        INCR_OVERFLOW => Err(KvError::Overflow),
+        _ => Err(KvError::NotAnInteger),
+    })
+}
@@ -1,6 +1,13 @@
 use assert_cmd::prelude::*;
+use ferris_log::kvstore::batch::WriteBatch;
+use ferris_log::kvstore::KvStore;
+use ferris_log::server::protocol::{
+    encode_batch, read_status, read_value, write_request, Header, BATCH, GET, SET,
+};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -151,6 +158,17 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4018", "--sync", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -253,3 +271,529 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Raw bytes, and values longer than a byte can count, should make it through the server untouched.
+fn binary_access_server(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let key = vec![0, 159, 146, 150, 255];
+    let val: Vec<u8> = (0..=255).cycle().take(1000).collect();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_request(&mut stream, SET, &key, &val).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(read_status(&mut stream).unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_request(&mut stream, GET, &key, &[]).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_value(&mut stream).unwrap(), val);
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn binary_access_server_kvs_engine() {
+    binary_access_server("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn binary_access_server_sled_engine() {
+    binary_access_server("sled", "127.0.0.1:4007");
+}
+
+// A header claiming more than the server takes should be refused without taking the server down.
+#[test]
+fn oversized_request_refused() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4019").unwrap();
+    let header = Header::new(SET, u32::MAX, u32::MAX);
+    stream.write_all(&header.encode()).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(read_value(&mut stream).is_err());
+
+    let mut stream = TcpStream::connect("127.0.0.1:4019").unwrap();
+    write_request(&mut stream, SET, b"key1", b"value1").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(read_status(&mut stream).unwrap());
+
+    let mut stream = TcpStream::connect("127.0.0.1:4019").unwrap();
+    write_request(&mut stream, GET, b"key1", &[]).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_value(&mut stream).unwrap(), b"value1");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+fn scan_access_server(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, val) in [("user:2", "b"), ("user:1", "a"), ("group:1", "c")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", addr, "set", key, val])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan-prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1\ta\nuser:2\tb\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan", "group:1", "user:2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("group:1\tc\nuser:1\ta\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("group:1\tc\nuser:1\ta\nuser:2\tb\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn scan_access_server_kvs_engine() {
+    scan_access_server("kvs", "127.0.0.1:4008");
+}
+
+#[test]
+fn scan_access_server_sled_engine() {
+    scan_access_server("sled", "127.0.0.1:4009");
+}
+
+fn ttl_access_server(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "session", "abc", "--ttl", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(100));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "ttl", "session"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "session"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("abc\n");
+
+    thread::sleep(Duration::from_millis(1200));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "session"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "ttl", "session"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn ttl_access_server_kvs_engine() {
+    ttl_access_server("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn ttl_access_server_sled_engine() {
+    ttl_access_server("sled", "127.0.0.1:4011");
+}
+
+fn batch_access_server(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_request(&mut stream, SET, b"pending", b"transfer").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(read_status(&mut stream).unwrap());
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"from".to_vec(), b"50".to_vec());
+    batch.set_with_ttl(b"to".to_vec(), b"50".to_vec(), Duration::from_secs(60));
+    batch.remove(b"pending".to_vec());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_request(&mut stream, BATCH, &[], &encode_batch(&batch)).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(read_status(&mut stream).unwrap());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "to"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("50\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "ttl", "to"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("60\n");
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_request(&mut stream, GET, b"from", &[]).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(read_value(&mut stream).unwrap(), b"50");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "pending"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn batch_access_server_kvs_engine() {
+    batch_access_server("kvs", "127.0.0.1:4012");
+}
+
+#[test]
+fn batch_access_server_sled_engine() {
+    batch_access_server("sled", "127.0.0.1:4013");
+}
+
+fn cas_access_server(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set-if-absent", "leader", "node-1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set-if-absent", "leader", "node-2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("Current value: node-1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "leader",
+            "--expected",
+            "node-2",
+            "--new",
+            "node-3",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("Current value: node-1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            addr,
+            "cas",
+            "leader",
+            "--expected",
+            "node-1",
+            "--new",
+            "node-3",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "leader"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("node-3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "remove-if-equals", "leader", "node-3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "remove-if-equals", "leader", "node-3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "cas", "leader", "--new", "node-4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cas_access_server_kvs_engine() {
+    cas_access_server("kvs", "127.0.0.1:4014");
+}
+
+#[test]
+fn cas_access_server_sled_engine() {
+    cas_access_server("sled", "127.0.0.1:4015");
+}
+
+fn incr_access_server(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // Clients counting at the same time shouldn't lose an increment
+    let clients: Vec<_> = (0..4)
+        .map(|_| {
+            let addr = addr.to_owned();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    Command::cargo_bin("kvs-client")
+                        .unwrap()
+                        .args(["--addr", &addr, "incr", "hits"])
+                        .assert()
+                        .success();
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "decr", "hits", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("38\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "name", "ferris"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(100));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "incr", "name"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("Value is not an integer\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "incr", "hits", &i64::MAX.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("Value would overflow\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn incr_access_server_kvs_engine() {
+    incr_access_server("kvs", "127.0.0.1:4016");
+}
+
+#[test]
+fn incr_access_server_sled_engine() {
+    incr_access_server("sled", "127.0.0.1:4017");
+}
+
+// Writes acknowledged by a server syncing every write should be there after it is killed.
+#[test]
+fn sync_access_server() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4020", "--sync", "always"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // The client only exits once the server answered, which it only does after the sync
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4020", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4020").unwrap();
+    write_request(&mut stream, SET, b"key2", b"value2").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(read_status(&mut stream).unwrap());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
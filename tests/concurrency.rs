@@ -3,9 +3,14 @@ use ferris_log::concurrency::naive::NaiveThreadPool;
 use ferris_log::concurrency::rayon::RayonThreadPool;
 use ferris_log::concurrency::shared::SharedQueueThreadPool;
 use ferris_log::concurrency::ThreadPool;
+use ferris_log::kv_engine::KvEngine;
 use ferris_log::kvstore::error::KvResult;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use ferris_log::kvstore::options::{CompactionPolicy, KvStoreOptions, ReadMode, SyncPolicy};
+use ferris_log::kvstore::KvStore;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use tempfile::TempDir;
 
 pub type Result<T> = KvResult<T>;
 
@@ -71,3 +76,141 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+// Transactions incrementing the same key from many threads shouldn't lose an increment.
+fn transaction_counter<E: KvEngine>(engine: E) {
+    const THREAD_NUM: usize = 4;
+    const ADD_COUNT: usize = 50;
+
+    let handles: Vec<_> = (0..THREAD_NUM)
+        .map(|_| {
+            let mut engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..ADD_COUNT {
+                    engine
+                        .ttransaction(|txn| {
+                            let count = match txn.tget(b"counter".to_vec())? {
+                                Some(count) => String::from_utf8(count)?.parse::<usize>()?,
+                                None => 0,
+                            };
+                            txn.tset(b"counter".to_vec(), (count + 1).to_string().into_bytes())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let count = engine.tget(b"counter".to_vec()).unwrap().unwrap();
+    assert_eq!(count, (THREAD_NUM * ADD_COUNT).to_string().into_bytes());
+}
+
+#[test]
+fn kvs_engine_transaction_counter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transaction_counter(KvStore::open(temp_dir.path())?);
+    Ok(())
+}
+
+#[test]
+fn sled_engine_transaction_counter() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transaction_counter(sled::open(temp_dir.path()).unwrap());
+}
+
+// Writers flushing together should each still only return once their own write is on the disk.
+#[test]
+fn group_commit_concurrent_writers() -> Result<()> {
+    const THREAD_NUM: usize = 8;
+    const SET_COUNT: usize = 50;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let handles: Vec<_> = (0..THREAD_NUM)
+        .map(|t| {
+            let mut store = store.clone();
+            thread::spawn(move || {
+                for i in 0..SET_COUNT {
+                    store.set(format!("key{}_{}", t, i), i.to_string()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.unsynced_bytes(), 0);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for t in 0..THREAD_NUM {
+        for i in 0..SET_COUNT {
+            assert_eq!(store.get(format!("key{}_{}", t, i))?, Some(i.to_string()));
+        }
+    }
+    Ok(())
+}
+
+// Readers on their own handles should always find a value, even while merges move the records.
+fn readers_during_compaction(mode: ReadMode) -> Result<()> {
+    const KEY_NUM: usize = 50;
+    const READER_NUM: usize = 4;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::StaleRatio {
+            ratio: 0.5,
+            min_size: 512,
+        })
+        .segment_size(1024)
+        .read_mode(mode)
+        .open(temp_dir.path())?;
+    for i in 0..KEY_NUM {
+        store.set(format!("key{}", i), "value0".to_owned())?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..READER_NUM)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    for i in 0..KEY_NUM {
+                        let val = store.get(format!("key{}", i)).unwrap().unwrap();
+                        assert!(val.starts_with("value"));
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for round in 1..20 {
+        for i in 0..KEY_NUM {
+            store.set(format!("key{}", i), format!("value{}", round))?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    store.wait_for_compaction()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value19".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn buffered_readers_during_compaction() -> Result<()> {
+    readers_during_compaction(ReadMode::Buffered)
+}
+
+#[test]
+fn mmap_readers_during_compaction() -> Result<()> {
+    readers_during_compaction(ReadMode::Mmap)
+}
@@ -1,9 +1,22 @@
 use assert_cmd::prelude::*;
+use ferris_log::kv_engine::KvEngine;
+use ferris_log::kvstore::batch::WriteBatch;
+use ferris_log::kvstore::command;
+use ferris_log::kvstore::crypto::EncryptionKey;
+use ferris_log::kvstore::error::KvError;
+use ferris_log::kvstore::expiry::Ttl;
+use ferris_log::kvstore::options::{
+    CompactionPolicy, Compression, HistoryPolicy, KvStoreOptions, ReadMode, SyncPolicy,
+};
+use ferris_log::kvstore::segment::SEGMENT_MAGIC;
 use ferris_log::kvstore::KvStore;
 use predicates::ord::eq;
 use predicates::str::{contains, PredicateStrExt};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -276,6 +289,8 @@ fn compaction() -> Result<(), Box<dyn Error>> {
             let value = format!("{}", iter);
             store.set(key, value)?;
         }
+        // Compaction runs in the background, let it finish before measuring.
+        store.wait_for_compaction()?;
 
         let new_size = dir_size();
         if new_size > current_size {
@@ -296,3 +311,1672 @@ fn compaction() -> Result<(), Box<dyn Error>> {
 
     panic!("No compaction detected");
 }
+
+// Writes past the segment size should seal the active segment and start a new one.
+#[test]
+fn segments_roll_over() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Disabled)
+        .segment_size(256)
+        .open(temp_dir.path())?;
+
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let segments = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+        .count();
+    assert!(segments > 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// A directory written before segments existed should still open.
+#[test]
+fn open_legacy_log() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("log.txt"),
+        "{\"Set\":{\"key\":\"key1\",\"val\":\"value1\"}}\n\
+         {\"Set\":{\"key\":\"key2\",\"val\":\"value2\"}}\n\
+         {\"Remove\":{\"key\":\"key2\"}}\n",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A record whose bytes changed on disk should fail its checksum instead of returning garbage.
+#[test]
+fn detect_corrupt_record() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_custom(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let segment = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&segment)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&segment, bytes)?;
+
+    assert!(store.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// A half written record at the end of the log should be cut off on open.
+#[test]
+fn recover_torn_tail() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_custom(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&segment)?.len();
+    let f = std::fs::OpenOptions::new().write(true).open(&segment)?;
+    f.set_len(len - 3)?;
+    drop(f);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let recovered = store.recovered().expect("torn tail should be reported");
+    assert_eq!(recovered.path, segment);
+    assert_eq!(recovered.offset + recovered.dropped, len - 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // The log should be usable again after the recovery.
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovered().is_none());
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Every write in a batch should land, and still be there after a reopen.
+#[test]
+fn write_batch() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "100".to_owned())?;
+    store.set("pending".to_owned(), "transfer".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"from".to_vec(), b"50".to_vec());
+    batch.set(b"to".to_vec(), b"50".to_vec());
+    batch.set_with_ttl(b"receipt".to_vec(), b"ok".to_vec(), Duration::from_secs(60));
+    batch.remove(b"pending".to_vec());
+    batch.remove(b"missing".to_vec());
+    store.write(batch)?;
+
+    let check = |store: &KvStore| -> Result<(), Box<dyn Error>> {
+        assert_eq!(store.get("from".to_owned())?, Some("50".to_owned()));
+        assert_eq!(store.get("to".to_owned())?, Some("50".to_owned()));
+        assert!(matches!(
+            store.ttl("receipt".to_owned())?,
+            Some(Ttl::Expires(_))
+        ));
+        assert_eq!(store.get("pending".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// A batch whose commit marker never made it to disk should be dropped whole on open.
+#[test]
+fn ignore_uncommitted_batch() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "100".to_owned())?;
+
+    let segment = temp_dir.path().join("1.log");
+    let begin = std::fs::metadata(&segment)?.len();
+    let mut batch = WriteBatch::new();
+    batch.set(b"from".to_vec(), b"50".to_vec());
+    batch.set(b"to".to_vec(), b"50".to_vec());
+    store.write(batch)?;
+    drop(store);
+
+    // Cut off the commit marker, which is a record with no key or value
+    let len = std::fs::metadata(&segment)?.len();
+    let f = std::fs::OpenOptions::new().write(true).open(&segment)?;
+    f.set_len(len - 13)?;
+    drop(f);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let recovered = store.recovered().expect("open batch should be reported");
+    assert_eq!(recovered.offset, begin);
+    assert_eq!(std::fs::metadata(&segment)?.len(), begin);
+    assert_eq!(store.get("from".to_owned())?, Some("100".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, None);
+
+    store.set("to".to_owned(), "0".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovered().is_none());
+    assert_eq!(store.get("to".to_owned())?, Some("0".to_owned()));
+
+    Ok(())
+}
+
+// Only sets and removes should make it into a batch, markers would be lost on replay.
+#[test]
+fn batch_refuses_markers() {
+    let ops = vec![
+        command::Command::set(b"x".to_vec(), b"1".to_vec()),
+        command::Command::BatchBegin { count: 5 },
+        command::Command::set(b"y".to_vec(), b"2".to_vec()),
+    ];
+    assert!(matches!(
+        WriteBatch::try_from(ops),
+        Err(KvError::InvalidBatch)
+    ));
+
+    let ops = vec![
+        command::Command::set(b"x".to_vec(), b"1".to_vec()),
+        command::Command::rm(b"y".to_vec()),
+    ];
+    assert_eq!(
+        WriteBatch::try_from(ops).map(|batch| batch.len()).ok(),
+        Some(2)
+    );
+}
+
+// A transaction should only commit if the keys it read are unchanged.
+#[test]
+fn transaction_conflict() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("balance".to_owned(), "100".to_owned())?;
+
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("balance".to_owned())?, Some("100".to_owned()));
+    assert_eq!(txn.get("missing".to_owned())?, None);
+    txn.set("balance".to_owned(), "90".to_owned());
+    txn.set("log".to_owned(), "-10".to_owned());
+    // Reads see the transaction's own writes, the store doesn't until the commit
+    assert_eq!(txn.get("balance".to_owned())?, Some("90".to_owned()));
+    assert_eq!(store.get("balance".to_owned())?, Some("100".to_owned()));
+    txn.commit()?;
+    assert_eq!(store.get("balance".to_owned())?, Some("90".to_owned()));
+    assert_eq!(store.get("log".to_owned())?, Some("-10".to_owned()));
+
+    // Writing a key that was read fails the commit, even with the same value
+    let mut txn = store.transaction();
+    txn.get("balance".to_owned())?;
+    txn.set("log".to_owned(), "-20".to_owned());
+    store.set("balance".to_owned(), "90".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvError::TransactionConflict)));
+    assert_eq!(store.get("log".to_owned())?, Some("-10".to_owned()));
+
+    // So does creating a key that was read as missing
+    let mut txn = store.transaction();
+    txn.get("missing".to_owned())?;
+    txn.set("log".to_owned(), "-20".to_owned());
+    store.set("missing".to_owned(), "here".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvError::TransactionConflict)));
+
+    // Compaction moves records around but doesn't change them
+    let mut txn = store.transaction();
+    txn.get("balance".to_owned())?;
+    txn.remove("log".to_owned())?;
+    store.compaction()?;
+    txn.commit()?;
+    assert_eq!(store.get("log".to_owned())?, None);
+
+    Ok(())
+}
+
+// Conditional writes should only go through when the key holds what they expect.
+#[test]
+fn compare_and_swap() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store
+        .set_if_absent(b"leader".to_vec(), b"node-1".to_vec())?
+        .is_ok());
+    let conflict = store
+        .set_if_absent(b"leader".to_vec(), b"node-2".to_vec())?
+        .unwrap_err();
+    assert_eq!(conflict.current, Some(b"node-1".to_vec()));
+    assert_eq!(conflict.proposed, Some(b"node-2".to_vec()));
+
+    let conflict = store
+        .compare_and_swap(
+            b"leader".to_vec(),
+            Some(b"node-2".to_vec()),
+            Some(b"node-3".to_vec()),
+        )?
+        .unwrap_err();
+    assert_eq!(conflict.current, Some(b"node-1".to_vec()));
+    assert!(store
+        .compare_and_swap(
+            b"leader".to_vec(),
+            Some(b"node-1".to_vec()),
+            Some(b"node-3".to_vec()),
+        )?
+        .is_ok());
+
+    assert!(store
+        .remove_if_equals(b"leader".to_vec(), b"node-1".to_vec())?
+        .is_err());
+    assert!(store
+        .remove_if_equals(b"leader".to_vec(), b"node-3".to_vec())?
+        .is_ok());
+    let conflict = store
+        .remove_if_equals(b"leader".to_vec(), b"node-3".to_vec())?
+        .unwrap_err();
+    assert_eq!(conflict.current, None);
+
+    // An expired key counts as not there
+    store.set_with_ttl("lock".to_owned(), "a".to_owned(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(store.set_if_absent(b"lock".to_vec(), b"b".to_vec())?.is_ok());
+    assert_eq!(store.ttl("lock".to_owned())?, Some(Ttl::Persistent));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("leader".to_owned())?, None);
+    assert_eq!(store.get("lock".to_owned())?, Some("b".to_owned()));
+
+    Ok(())
+}
+
+// Counters should start at 0 and only count values that are integers.
+#[test]
+fn incr_counters() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("hits".to_owned(), 1)?, 1);
+    assert_eq!(store.incr("hits".to_owned(), 41)?, 42);
+    assert_eq!(store.decr("hits".to_owned(), 50)?, -8);
+    assert_eq!(store.get("hits".to_owned())?, Some("-8".to_owned()));
+
+    store.set("name".to_owned(), "ferris".to_owned())?;
+    assert!(matches!(
+        store.incr("name".to_owned(), 1),
+        Err(KvError::NotAnInteger)
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("max".to_owned(), 1),
+        Err(KvError::Overflow)
+    ));
+    assert!(matches!(
+        store.decr("hits".to_owned(), i64::MIN),
+        Err(KvError::Overflow)
+    ));
+
+    // Counting keeps the TTL
+    store.set_with_ttl("window".to_owned(), "5".to_owned(), Duration::from_secs(60))?;
+    assert_eq!(store.incr("window".to_owned(), 1)?, 6);
+    assert!(matches!(
+        store.ttl("window".to_owned())?,
+        Some(Ttl::Expires(_))
+    ));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hits".to_owned())?, Some("-8".to_owned()));
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+
+    Ok(())
+}
+
+// Sled keys written by a compare and swap or a fresh counter shouldn't keep an old TTL.
+#[test]
+fn sled_conditional_writes_clear_ttl() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut db = sled::open(temp_dir.path())?;
+
+    db.tset_with_ttl(b"lock".to_vec(), b"a".to_vec(), Duration::from_secs(60))?;
+    assert!(db.tremove_if_equals(b"lock".to_vec(), b"a".to_vec())?.is_ok());
+    assert_eq!(db.tttl(b"lock".to_vec())?, None);
+    assert_eq!(db.tincr(b"lock".to_vec(), 1)?, 1);
+    assert_eq!(db.tttl(b"lock".to_vec())?, Some(Ttl::Persistent));
+
+    // An expired key counts as not there
+    db.tset_with_ttl(b"lease".to_vec(), b"a".to_vec(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(db
+        .tcompare_and_swap(b"lease".to_vec(), None, Some(b"b".to_vec()))?
+        .is_ok());
+    assert_eq!(db.tttl(b"lease".to_vec())?, Some(Ttl::Persistent));
+
+    db.tset_with_ttl(b"hits".to_vec(), b"5".to_vec(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(db.tincr(b"hits".to_vec(), 1)?, 1);
+    assert_eq!(db.tttl(b"hits".to_vec())?, Some(Ttl::Persistent));
+
+    Ok(())
+}
+
+// A snapshot should keep seeing the store as it was when it was taken.
+#[test]
+fn snapshot_reads() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_custom(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    store.set("c".to_owned(), "1".to_owned())?;
+
+    let snapshot = store.snapshot();
+    store.set("a".to_owned(), "2".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.set("d".to_owned(), "2".to_owned())?;
+    let later = store.snapshot();
+    store.set("a".to_owned(), "3".to_owned())?;
+
+    assert_eq!(snapshot.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("b".to_owned())?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("d".to_owned())?, None);
+    let pairs: Vec<_> = snapshot.scan(..)?.collect();
+    assert_eq!(
+        pairs,
+        vec![
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"b".to_vec(), b"1".to_vec()),
+            (b"c".to_vec(), b"1".to_vec()),
+        ]
+    );
+    assert_eq!(later.get("a".to_owned())?, Some("2".to_owned()));
+    assert_eq!(later.scan_prefix(b"b")?.count(), 0);
+    assert_eq!(store.get("a".to_owned())?, Some("3".to_owned()));
+
+    // Compaction has to keep the versions the snapshots read
+    store.compaction()?;
+    assert_eq!(snapshot.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("b".to_owned())?, Some("1".to_owned()));
+    assert_eq!(later.get("a".to_owned())?, Some("2".to_owned()));
+
+    // Once they are gone the next compaction can drop those versions
+    drop(snapshot);
+    drop(later);
+    assert!(store.stale_bytes() > 0);
+    store.compaction()?;
+    assert_eq!(store.stale_bytes(), 0);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.count(), 3);
+
+    Ok(())
+}
+
+// Should keep the last versions of a key with when they were written, across compaction and
+// reopening the store.
+#[test]
+fn history_versions() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Disabled)
+        .history(HistoryPolicy::Versions(3));
+    let mut store = options.open(temp_dir.path())?;
+    for val in ["1", "2", "3"] {
+        store.set("a".to_owned(), val.to_owned())?;
+        thread::sleep(Duration::from_millis(5));
+    }
+    store.remove("a".to_owned())?;
+    thread::sleep(Duration::from_millis(5));
+    store.set("a".to_owned(), "4".to_owned())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<(), Box<dyn Error>> {
+        let history = store.get_history("a".to_owned())?;
+        let values: Vec<_> = history.iter().map(|version| version.value.clone()).collect();
+        assert_eq!(values, vec![Some(b"4".to_vec()), None, Some(b"3".to_vec())]);
+
+        let times: Vec<u64> = history.iter().map(|version| version.written_at.unwrap()).collect();
+        assert!(times[0] > times[1] && times[1] > times[2]);
+        assert_eq!(store.get_at("a".to_owned(), times[2])?, Some("3".to_owned()));
+        assert_eq!(store.get_at("a".to_owned(), times[1])?, None);
+        assert_eq!(store.get_at("a".to_owned(), times[0] + 1)?, Some("4".to_owned()));
+        // Older than anything that is kept
+        assert_eq!(store.get_at("a".to_owned(), times[2] - 1)?, None);
+        assert_eq!(store.get_history("b".to_owned())?.len(), 1);
+        assert!(store.get_history("c".to_owned())?.is_empty());
+        Ok(())
+    };
+    check(&store)?;
+
+    store.compaction()?;
+    check(&store)?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+
+    // Without the policy only the latest version is there
+    drop(store);
+    let store = KvStore::open_custom(temp_dir.path())?;
+    let history = store.get_history("a".to_owned())?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value, Some(b"4".to_vec()));
+
+    Ok(())
+}
+
+// Should drop the versions that stopped being the latest before the window.
+#[test]
+fn history_window() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .history(HistoryPolicy::Window(Duration::from_millis(300)))
+        .open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("a".to_owned(), "2".to_owned())?;
+    thread::sleep(Duration::from_millis(400));
+    store.set("a".to_owned(), "3".to_owned())?;
+
+    let values: Vec<_> = store
+        .get_history("a".to_owned())?
+        .into_iter()
+        .map(|version| version.value)
+        .collect();
+    assert_eq!(values, vec![Some(b"3".to_vec()), Some(b"2".to_vec())]);
+
+    Ok(())
+}
+
+// Should compress large values, read them back with or without compression turned on, and
+// rewrite them the way the store is opened with on compaction.
+#[test]
+fn compressed_values() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let doc = r#"{"name":"ferris","tags":["crab","rust"],"score":42}"#.repeat(100);
+    let compressed = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Disabled)
+        .compression(Compression::Lz4 { min_size: 64 });
+
+    let mut store = compressed.open(temp_dir.path())?;
+    store.set("doc".to_owned(), doc.clone())?;
+    store.set("small".to_owned(), "tiny".to_owned())?;
+    assert!(store.disk_size() < doc.len() as u64 / 4);
+    assert_eq!(store.get("doc".to_owned())?, Some(doc.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("tiny".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open_custom(temp_dir.path())?;
+    assert_eq!(store.get("doc".to_owned())?, Some(doc.clone()));
+    store.compaction()?;
+    assert!(store.disk_size() > doc.len() as u64);
+    assert_eq!(store.get("doc".to_owned())?, Some(doc.clone()));
+
+    drop(store);
+    let mut store = compressed.open(temp_dir.path())?;
+    store.compaction()?;
+    assert!(store.disk_size() < doc.len() as u64 / 4);
+    assert_eq!(store.get("doc".to_owned())?, Some(doc));
+    assert_eq!(store.get("small".to_owned())?, Some("tiny".to_owned()));
+
+    Ok(())
+}
+
+// Should only lose the writes that weren't flushed yet when the power goes out, which is what the
+// sync policy decides.
+#[test]
+fn sync_policies() -> Result<(), Box<dyn Error>> {
+    // Cuts off everything that wasn't flushed, like a power failure would, and reopens the store
+    fn lose_power(dir: &std::path::Path, store: KvStore) -> Result<KvStore, Box<dyn Error>> {
+        let unsynced = store.unsynced_bytes();
+        drop(store);
+        let segment = dir.join("1.log");
+        let len = std::fs::metadata(&segment)?.len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&segment)?
+            .set_len(len - unsynced)?;
+        Ok(KvStore::open(dir)?)
+    }
+    let open = |dir: &TempDir, policy| {
+        KvStoreOptions::new()
+            .compaction(CompactionPolicy::Disabled)
+            .sync(policy)
+            .open(dir.path())
+    };
+
+    // Every write is on the disk by the time it returns
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(&temp_dir, SyncPolicy::Always)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.unsynced_bytes(), 0);
+    let store = lose_power(temp_dir.path(), store)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Only what was flushed by hand survives
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(&temp_dir, SyncPolicy::Never)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.sync()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.unsynced_bytes() > 0);
+    let store = lose_power(temp_dir.path(), store)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.recovered().is_none());
+
+    // Writes are flushed within the interval
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(&temp_dir, SyncPolicy::Periodic(Duration::from_millis(50)))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.unsynced_bytes(), 0);
+    let store = lose_power(temp_dir.path(), store)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should keep keys and values off the disk in the clear, and fail to open with the wrong key.
+#[test]
+fn encrypted_store() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::new([7; 32]);
+    let other = EncryptionKey::new([8; 32]);
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Disabled)
+        .encryption(key.clone());
+
+    let mut store = options.open(temp_dir.path())?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("other".to_owned())?;
+    let snapshot = store.create_snapshot()?;
+    assert_eq!(store.get("secret-key".to_owned())?, Some("secret-value".to_owned()));
+
+    let in_the_clear = |path: &std::path::Path| {
+        let bytes = std::fs::read(path).unwrap();
+        bytes.windows(6).any(|window| window == b"secret")
+    };
+    assert!(!in_the_clear(&temp_dir.path().join("1.log")));
+    assert!(!in_the_clear(&snapshot));
+
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::WrongKey { key_id }) => assert_eq!(key_id, key.id()),
+        res => panic!("opened without the key: {:?}", res.map(|_| ())),
+    }
+    match KvStoreOptions::new()
+        .encryption(other.clone())
+        .open(temp_dir.path())
+    {
+        Err(KvError::WrongKey { key_id }) => assert_eq!(key_id, key.id()),
+        res => panic!("opened with the wrong key: {:?}", res.map(|_| ())),
+    }
+
+    // The hint a merge writes is sealed too
+    let mut store = options.open(temp_dir.path())?;
+    store.compaction()?;
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("secret-key".to_owned())?, Some("secret-value".to_owned()));
+
+    // A snapshot loads back with the same key
+    store.set("secret-key".to_owned(), "changed".to_owned())?;
+    store.load_snapshot(snapshot)?;
+    assert_eq!(store.get("secret-key".to_owned())?, Some("secret-value".to_owned()));
+    assert_eq!(store.count(), 1);
+
+    Ok(())
+}
+
+// Should read records written with an old key and rewrite them with the new one on compaction.
+#[test]
+fn rotate_encryption_key() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = EncryptionKey::new([1; 32]);
+    let new = EncryptionKey::new([2; 32]);
+
+    let mut store = KvStoreOptions::new()
+        .encryption(old.clone())
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStoreOptions::new()
+        .encryption(new.clone())
+        .decryption_key(old.clone())
+        .open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.compaction()?;
+    drop(store);
+
+    let store = KvStoreOptions::new()
+        .encryption(new.clone())
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    match KvStoreOptions::new().encryption(old).open(temp_dir.path()) {
+        Err(KvError::WrongKey { key_id }) => assert_eq!(key_id, new.id()),
+        res => panic!("opened with the retired key: {:?}", res.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Corruption before the last record is not a torn write and should fail the open.
+#[test]
+fn corrupt_middle_record_fails_open() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_custom(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&segment)?;
+    // Flip a byte of the first value, just after the segment header.
+    bytes[5 + 13 + 4] ^= 0xff;
+    std::fs::write(&segment, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::CorruptLog { path, offset }) => {
+            assert_eq!(path, segment);
+            assert_eq!(offset, 5);
+        }
+        other => panic!("expected a corrupt log error, got {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// A corrupt length running past the end of the log is not a torn write if records follow it.
+#[test]
+fn corrupt_length_fails_open() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_custom(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&segment)?.len();
+    let mut bytes = std::fs::read(&segment)?;
+    // Set the high byte of the first record's val_len.
+    bytes[5 + 12] = 0x7f;
+    std::fs::write(&segment, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::CorruptLog { path, offset }) => {
+            assert_eq!(path, segment);
+            assert_eq!(offset, 5);
+        }
+        other => panic!("expected a corrupt log error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(std::fs::metadata(&segment)?.len(), len);
+
+    Ok(())
+}
+
+// Writes made while a compaction is running should survive it and a reopen.
+#[test]
+fn write_during_background_compaction() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut writer = store.clone();
+    let handle = std::thread::spawn(move || {
+        for iter in 0..5 {
+            for key_id in 0..200 {
+                writer
+                    .set(format!("key{}", key_id), format!("{}", iter))
+                    .unwrap();
+            }
+        }
+    });
+    for _ in 0..5 {
+        store.compaction()?;
+    }
+    handle.join().unwrap();
+    store.wait_for_compaction()?;
+
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("4".to_owned()));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("4".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Segments should be written under the configured data file name.
+#[test]
+fn custom_data_file_name() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().data_file_name("data");
+    let mut store = options.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("1.data").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Names the segments couldn't be told apart from the store's other files under are refused.
+#[test]
+fn invalid_data_file_name() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for name in ["", "data.log", "a/b", "compact", "hint", "blob", "upgrade"] {
+        let res = KvStoreOptions::new()
+            .data_file_name(name)
+            .open(temp_dir.path());
+        assert!(matches!(res, Err(KvError::InvalidFileName { .. })));
+    }
+}
+
+// With compaction disabled the log should only ever grow.
+#[test]
+fn compaction_disabled() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Disabled)
+        .open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.wait_for_compaction()?;
+
+    let log_size = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    store.compaction()?;
+    assert!(std::fs::metadata(temp_dir.path().join("2.log"))?.len() < log_size);
+    assert_eq!(store.get("key7".to_owned())?, Some("9".to_owned()));
+
+    Ok(())
+}
+
+// Overwritten and removed records should be counted as stale until a compaction drops them.
+#[test]
+fn track_stale_bytes() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_custom(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stale_bytes(), 0);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let record_len = store.stale_bytes();
+    assert!(record_len > 0);
+
+    store.remove("key2".to_owned())?;
+    assert!(store.stale_bytes() > 2 * record_len);
+
+    // Reopening should count the same garbage.
+    let stale = store.stale_bytes();
+    drop(store);
+    let mut store = KvStore::open_custom(temp_dir.path())?;
+    assert_eq!(store.stale_bytes(), stale);
+
+    store.compaction()?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A log with only live records should never be compacted, however big it gets.
+#[test]
+fn no_compaction_without_garbage() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .compaction(CompactionPolicy::StaleRatio {
+            ratio: 0.4,
+            min_size: 0,
+        })
+        .open(temp_dir.path())?;
+
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.wait_for_compaction()?;
+    assert!(temp_dir.path().join("1.log").exists());
+    assert!(!temp_dir.path().join("2.log").exists());
+
+    // Overwriting everything makes half the log stale, which should trigger a merge.
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.wait_for_compaction()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get("key499".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should leave a hint next to its output, and open should load it instead of reading
+// the segment.
+#[test]
+fn open_from_hint() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::Disabled);
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+        store.set(format!("key{}", key_id), format!("{}", key_id))?;
+    }
+    store.compaction()?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("2.log.hint").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    // The hint already says where every record is, so a damaged one isn't noticed until it's read
+    let segment = temp_dir.path().join("2.log");
+    let mut bytes = std::fs::read(&segment)?;
+    bytes[5 + 13] ^= 0xff;
+    std::fs::write(&segment, bytes)?;
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let mut damaged = 0;
+    for key_id in 3..20 {
+        match store.get(format!("key{}", key_id)) {
+            Ok(val) => assert_eq!(val, Some(format!("{}", key_id))),
+            Err(KvError::ChecksumError) => damaged += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    assert!(damaged <= 1);
+
+    Ok(())
+}
+
+// A hint that doesn't check out should be ignored and its segment replayed.
+#[test]
+fn ignore_corrupt_hint() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction(CompactionPolicy::Disabled);
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("{}", key_id))?;
+    }
+    store.compaction()?;
+    drop(store);
+
+    let hint = temp_dir.path().join("2.log.hint");
+    let mut bytes = std::fs::read(&hint)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&hint, bytes)?;
+
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", key_id))
+        );
+    }
+    assert_eq!(store.count(), 20);
+
+    Ok(())
+}
+
+// Keys and values should come back byte for byte, even when they aren't text.
+#[test]
+fn binary_keys_and_values() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let key = vec![0, 159, 146, 150, 255];
+    let val: Vec<u8> = (0..=255).cycle().take(4096).collect();
+    store.set_bytes(key.clone(), val.clone())?;
+    store.set_bytes(b"text".to_vec(), vec![0xc3, 0x28])?;
+    assert_eq!(store.get_bytes(&key)?, Some(val.clone()));
+
+    // Only the String API cares about UTF-8
+    assert!(matches!(
+        store.get("text".to_owned()),
+        Err(KvError::ParseError)
+    ));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(val));
+    store.compaction()?;
+    assert_eq!(store.get_bytes(b"text")?, Some(vec![0xc3, 0x28]));
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+
+    Ok(())
+}
+
+// Scans should return the keys in order, whatever order they were written in.
+#[test]
+fn scan_in_key_order() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["user:2:profile", "order:1", "user:10:name", "user:1:profile", "user:1:name"] {
+        store.set(key.to_owned(), format!("{}-val", key))?;
+    }
+    store.remove("user:10:name".to_owned())?;
+
+    let keys = |scan: ferris_log::kvstore::Scan| -> Vec<String> {
+        scan.map(|(key, _)| String::from_utf8(key).unwrap()).collect()
+    };
+
+    assert_eq!(
+        keys(store.scan_prefix(b"user:1:")?),
+        vec!["user:1:name", "user:1:profile"]
+    );
+    assert_eq!(
+        keys(store.scan(b"order".to_vec()..b"user:2".to_vec())?),
+        vec!["order:1", "user:1:name", "user:1:profile"]
+    );
+    assert_eq!(keys(store.scan(..)?).len(), 4);
+    assert_eq!(keys(store.scan(b"z".to_vec()..b"a".to_vec())?).len(), 0);
+
+    let (key, val) = store.scan_prefix(b"user:2")?.next().unwrap();
+    assert_eq!(key, b"user:2:profile");
+    assert_eq!(val, b"user:2:profile-val");
+
+    Ok(())
+}
+
+// `kvs scan-prefix <PREFIX>` should print the matching pairs in key order.
+#[test]
+fn cli_scan_prefix() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:2".to_owned(), "b".to_owned())?;
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    store.set("group:1".to_owned(), "c".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan-prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user:1\ta\nuser:2\tb\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "a", "h"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("group:1\tc\n"));
+
+    Ok(())
+}
+
+// A key set with a TTL should be gone once it runs out, also after a reopen.
+#[test]
+fn ttl_expires_keys() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sweep_interval(Duration::from_secs(3600));
+    let mut store = options.open(temp_dir.path())?;
+    store.set_with_ttl("session".to_owned(), "abc".to_owned(), Duration::from_millis(200))?;
+    store.set("user".to_owned(), "ferris".to_owned())?;
+
+    assert_eq!(store.get("session".to_owned())?, Some("abc".to_owned()));
+    match store.ttl("session".to_owned())? {
+        Some(Ttl::Expires(left)) => assert!(left <= Duration::from_millis(200)),
+        ttl => panic!("unexpected ttl {:?}", ttl),
+    }
+    assert_eq!(store.ttl("user".to_owned())?, Some(Ttl::Persistent));
+    assert_eq!(store.ttl("missing".to_owned())?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.count(), 1);
+    assert_eq!(store.scan(..)?.count(), 1);
+    assert_eq!(store.ttl("session".to_owned())?, None);
+    assert!(store.remove("session".to_owned()).is_err());
+    assert_eq!(store.get("session".to_owned())?, None);
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert_eq!(store.get("user".to_owned())?, Some("ferris".to_owned()));
+
+    Ok(())
+}
+
+// Expired keys should be removed in the background even if nobody reads them.
+#[test]
+fn sweeper_removes_expired_keys() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Disabled)
+        .sweep_interval(Duration::from_millis(50));
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+    }
+    let written = store.disk_size();
+    assert_eq!(store.stale_bytes(), 0);
+
+    thread::sleep(Duration::from_millis(500));
+
+    // Every record is stale now, along with the removals that were written for them. Only the
+    // magic at the start of the segment isn't
+    assert!(store.disk_size() > written);
+    assert_eq!(
+        store.stale_bytes() + SEGMENT_MAGIC.len() as u64,
+        store.disk_size()
+    );
+    drop(store);
+
+    // Reopening counts the same size as the writes did
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(
+        store.stale_bytes() + SEGMENT_MAGIC.len() as u64,
+        store.disk_size()
+    );
+
+    Ok(())
+}
+
+// Compaction should leave expired records behind.
+#[test]
+fn compaction_drops_expired_keys() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction(CompactionPolicy::Disabled)
+        .sweep_interval(Duration::from_secs(3600));
+    let mut store = options.open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(50),
+        )?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+
+    thread::sleep(Duration::from_millis(100));
+    store.compaction()?;
+
+    // The segment header and a single record with its 13 byte header
+    let kept_len = 5 + 13 + "kept".len() + "value".len();
+    assert_eq!(
+        std::fs::metadata(temp_dir.path().join("2.log"))?.len(),
+        kept_len as u64
+    );
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.scan(..)?.count(), 1);
+
+    Ok(())
+}
+
+// `kvs set <KEY> <VALUE> --ttl <SECONDS>` should set a key that `kvs ttl <KEY>` reports.
+#[test]
+fn cli_ttl() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "session", "abc", "--ttl", "60"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "session"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("60").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "user", "ferris"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "user"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("No expiry").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "missing"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+}
+
+// `kvs incr <KEY> [DELTA]` should print the new value.
+#[test]
+fn cli_incr() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "hits"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "hits", "-5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("-4").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["decr", "hits", "6"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("-10").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "name", "ferris"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "name"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Value is not an integer").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "hits", &i64::MAX.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "hits", &i64::MAX.to_string()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Value would overflow").trim());
+}
+// `kvs history <KEY>` should list the versions kept, newest first.
+#[test]
+fn cli_history() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for args in [
+        ["set", "key1", "value1"],
+        ["set", "key1", "value2"],
+        ["set", "key1", "value3"],
+    ] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["--history", "3"])
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--history", "3", "rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--history", "3", "history", "key1"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let values: Vec<_> = stdout
+        .lines()
+        .map(|line| line.split('\t').nth(1).unwrap())
+        .collect();
+    assert_eq!(values, vec!["(removed)", "value3", "value2"]);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["history", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+}
+
+// `kvs --key-file` should encrypt the store, which then needs the key to be read.
+#[test]
+fn cli_encrypted() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key_file = temp_dir.path().join("key");
+    std::fs::write(&key_file, "11".repeat(32)).unwrap();
+    let data_dir = temp_dir.path().join("data");
+    std::fs::create_dir(&data_dir).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--key-file"])
+        .arg(&key_file)
+        .current_dir(&data_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--key-file"])
+        .arg(&key_file)
+        .current_dir(&data_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&data_dir)
+        .assert()
+        .failure()
+        .stderr(contains("encrypted"));
+}
+// A second store shouldn't be able to open a directory that is already open.
+#[test]
+fn directory_lock() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::Locked { .. })
+    ));
+    assert!(matches!(
+        KvStoreOptions::new().read_only(true).open(temp_dir.path()),
+        Err(KvError::Locked { .. })
+    ));
+
+    // Clones share the lock, it is only let go of with the last one
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Read-only stores should open side by side, refuse writes and leave the log as it is.
+#[test]
+fn read_only_open() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    drop(store);
+
+    // A torn write at the end of the log
+    let segment = temp_dir.path().join("1.log");
+    let mut f = std::fs::OpenOptions::new().append(true).open(&segment)?;
+    std::io::Write::write_all(&mut f, &[0, 1, 2])?;
+    drop(f);
+    let len = std::fs::metadata(&segment)?.len();
+    thread::sleep(Duration::from_millis(10));
+
+    let options = KvStoreOptions::new().read_only(true);
+    let mut first = options.open(temp_dir.path())?;
+    let second = options.open(temp_dir.path())?;
+    assert!(first.recovered().is_some());
+    for store in [&first, &second] {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+
+    assert!(matches!(
+        first.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvError::ReadOnly)
+    ));
+    assert!(matches!(
+        first.remove("key1".to_owned()),
+        Err(KvError::ReadOnly)
+    ));
+    assert!(matches!(first.compaction(), Err(KvError::ReadOnly)));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::Locked { .. })
+    ));
+    assert_eq!(std::fs::metadata(&segment)?.len(), len);
+
+    drop(first);
+    drop(second);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovered().is_some());
+    assert!(std::fs::metadata(&segment)?.len() < len);
+
+    Ok(())
+}
+
+// A read-only store shouldn't create the lock file in a directory no store has written to.
+#[test]
+fn read_only_no_lock_file() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .read_only(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!temp_dir.path().join("LOCK").exists());
+    drop(store);
+
+    KvStore::open(temp_dir.path())?;
+    assert!(temp_dir.path().join("LOCK").exists());
+
+    Ok(())
+}
+
+// `kvs` should refuse a directory that is already open, and `--read-only` shouldn't write.
+#[test]
+fn cli_read_only() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already open"));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--read-only", "set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+
+    Ok(())
+}
+// Cached values should be counted, go away when they're overwritten and stay within the size.
+#[test]
+fn value_cache() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.cache_stats(), None);
+    drop(store);
+
+    store = KvStoreOptions::new()
+        .cache_size(16 * 1024)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.cache_stats().unwrap().entries, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.compaction()?;
+    assert_eq!(store.cache_stats().unwrap().entries, 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Far more than fits, the cache evicts to stay within its size
+    let val = "v".repeat(512);
+    for i in 0..200 {
+        store.set(format!("big{}", i), val.clone())?;
+    }
+    for i in 0..200 {
+        assert_eq!(store.get(format!("big{}", i))?, Some(val.clone()));
+    }
+    let stats = store.cache_stats().unwrap();
+    assert!(stats.evictions > 0);
+    assert!(stats.bytes <= 16 * 1024);
+
+    Ok(())
+}
+// Reads through a memory-mapped segment should see what was appended after it was mapped, and
+// the merged segments after a compaction
+#[test]
+fn mmap_reads() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .read_mode(ReadMode::Mmap)
+        .compaction(CompactionPolicy::Disabled)
+        .segment_size(4 * 1024);
+    let mut store = options.open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Spread over many segments, overwriting every key a few times
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter))
+            );
+        }
+    }
+    store.compaction()?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    // Other handles map the segments on their own
+    let handle = store.clone();
+    store.remove("key1".to_owned())?;
+    assert_eq!(handle.get("key1".to_owned())?, None);
+    assert_eq!(handle.get("key2".to_owned())?, Some("9".to_owned()));
+    drop(handle);
+
+    drop(store);
+    store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    for key_id in 2..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn blob_files(dir: &std::path::Path) -> usize {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".blob"))
+        .count()
+}
+
+// Values past the blob threshold should go to blob files, which merges only rewrite once they
+// are mostly garbage
+#[test]
+fn blob_storage() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .blob_threshold(1024)
+        .compaction(CompactionPolicy::Disabled)
+        .segment_size(64 * 1024);
+    let mut store = options.open(temp_dir.path())?;
+
+    let big = |i: usize| format!("{}", i).repeat(2048);
+    store.set("small".to_owned(), "value".to_owned())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), big(i))?;
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(big(i)));
+    }
+    // The log only holds where the values are
+    assert!(store.disk_size() < 1024);
+    assert!(store.blob_size() > 10 * 2048);
+    assert_eq!(blob_files(temp_dir.path()), 1);
+
+    // Overwriting the values leaves the first blob file all garbage, merging rewrites it
+    for i in 0..10 {
+        store.set(format!("key{}", i), big(i + 1))?;
+    }
+    store.remove("key9".to_owned())?;
+    let before = store.blob_size();
+    store.compaction()?;
+    assert!(store.blob_size() < before);
+    for i in 0..9 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(big(i + 1)));
+    }
+    assert_eq!(store.get("key9".to_owned())?, None);
+
+    // Merging again leaves blob files without garbage alone
+    let before = store.blob_size();
+    store.set("small".to_owned(), "value2".to_owned())?;
+    store.compaction()?;
+    assert_eq!(store.blob_size(), before);
+
+    // Both from the hint and from replaying the log
+    store.set_with_ttl("key0".to_owned(), big(7), Duration::from_secs(60))?;
+    drop(store);
+    store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some(big(7)));
+    for i in 1..9 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(big(i + 1)));
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("value2".to_owned()));
+
+    // A snapshot holds the values itself
+    let snapshot = store.create_snapshot()?;
+    store.set("key1".to_owned(), "overwritten".to_owned())?;
+    store.load_snapshot(snapshot)?;
+    assert_eq!(blob_files(temp_dir.path()), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some(big(2)));
+
+    Ok(())
+}
+
+// Values in blob files should be encrypted like the rest of the store
+#[test]
+fn blob_storage_encrypted() -> Result<(), Box<dyn Error>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::new([9; 32]);
+    let options = KvStoreOptions::new()
+        .blob_threshold(64)
+        .encryption(key.clone());
+    let mut store = options.open(temp_dir.path())?;
+    let secret = "secret".repeat(100);
+    store.set("key1".to_owned(), secret.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(secret.clone()));
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path()) {
+        let path = entry?.into_path();
+        if path.is_file() {
+            let bytes = std::fs::read(&path)?;
+            assert!(!bytes.windows(6).any(|window| window == b"secret"));
+        }
+    }
+
+    store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(secret.clone()));
+    drop(store);
+
+    // Compacting with the old key as well moves the values over to the new one
+    let new = EncryptionKey::new([10; 32]);
+    store = KvStoreOptions::new()
+        .blob_threshold(64)
+        .encryption(new.clone())
+        .decryption_key(key)
+        .open(temp_dir.path())?;
+    store.compaction()?;
+    drop(store);
+
+    store = KvStoreOptions::new()
+        .blob_threshold(64)
+        .encryption(new)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(secret));
+
+    Ok(())
+}